@@ -14,7 +14,7 @@
 
 use std::time::Duration;
 
-use await_tree::{Config, ConfigBuilder, InstrumentAwait, Registry};
+use await_tree::{Config, ConfigBuilder, InstrumentAwait, Registry, Span};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use tokio::runtime::{Builder, Runtime};
 use tokio::task::yield_now;
@@ -127,6 +127,21 @@ fn bench_basic_baseline(c: &mut Criterion) {
 
 criterion_group!(benches, bench_basic, bench_basic_baseline);
 
+fn bench_span(c: &mut Criterion) {
+    let short = "short".to_owned();
+    let long = "a span name longer than the inline capacity".to_owned();
+
+    c.bench_function("span_inline", |b| {
+        b.iter(|| Span::from_inline(black_box(&short)))
+    });
+    c.bench_function("span_heap", |b| b.iter(|| Span::from(black_box(&long))));
+
+    static STATIC: Span = Span::from_static("a span name longer than the inline capacity");
+    c.bench_function("span_static", |b| b.iter(|| black_box(&STATIC).clone()));
+}
+
+criterion_group!(span, bench_span);
+
 // with_register_to_root   time:   [15.993 ms 16.122 ms 16.292 ms]
 // baseline                time:   [13.940 ms 13.961 ms 13.982 ms]
 
@@ -150,4 +165,4 @@ criterion_group!(
     targets = bench_many_exp, bench_many_baseline
 );
 
-criterion_main!(benches, bench_many);
+criterion_main!(benches, span, bench_many);
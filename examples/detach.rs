@@ -66,7 +66,7 @@ async fn main() {
 
     // work [1.112s]
     //   rx [606.944ms]
-    // [Detached 4]
+    // [Detached 4: select-arm]
     //   fut [1.112s]
     println!("{tree}");
 
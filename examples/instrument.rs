@@ -0,0 +1,86 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This example shows how to spawn tasks whose root span is derived from the key.
+
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use await_tree::{init_global_registry, spawn_derived_root, Config, InstrumentAwait, Registry};
+use futures::future::{join, pending};
+use tokio::time::sleep;
+
+/// The root span is derived from the key through its `Display` implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Worker {
+    Compactor(usize),
+    Flusher,
+}
+
+impl Display for Worker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Worker::Compactor(i) => write!(f, "compactor {i}"),
+            Worker::Flusher => write!(f, "flusher"),
+        }
+    }
+}
+
+async fn simple() {
+    pending::<()>().instrument_await("pending").await
+}
+
+async fn complex() {
+    join(
+        simple().instrument_await("simple"),
+        sleep(Duration::from_secs(10)).instrument_await("sleep"),
+    )
+    .await;
+}
+
+#[tokio::main]
+async fn main() {
+    init_global_registry(Config::default());
+
+    // The root span of the task is derived from the key, i.e., "complex".
+    spawn_derived_root("complex", complex());
+    // Spawning in a specific registry is also supported.
+    Registry::current().spawn_derived_root(String::from("simple"), simple());
+    // Any key implementing `Display` works.
+    spawn_derived_root(Worker::Compactor(1), simple());
+    spawn_derived_root(Worker::Flusher, simple());
+
+    sleep(Duration::from_secs(1)).await;
+
+    for (key, tree) in Registry::current().collect_all() {
+        // [complex]
+        // complex [1.003s]
+        //   simple [1.003s]
+        //     pending [1.003s]
+        //   sleep [1.003s]
+        //
+        // [simple]
+        // simple [1.003s]
+        //   pending [1.003s]
+        //
+        // [Compactor(1)]
+        // compactor 1 [1.003s]
+        //   pending [1.003s]
+        //
+        // [Flusher]
+        // flusher [1.003s]
+        //   pending [1.003s]
+        println!("[{}]\n{}\n", key, tree);
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use crate::{Key, Registry, Span};
+
+/// Runs the given future to completion on the current thread, instrumented with the given root
+/// [`Span`].
+///
+/// The future will be registered in the current [`Registry`] returned by
+/// [`Registry::try_current`] with the given [`Key`], if it exists. Otherwise, the future is run as
+/// is.
+///
+/// See [`Registry::block_on`] for more details.
+pub fn block_on<F: Future>(key: impl Key, root_span: impl Into<Span>, future: F) -> F::Output {
+    if let Some(registry) = Registry::try_current() {
+        registry.block_on(key, root_span, future)
+    } else {
+        run(future)
+    }
+}
+
+impl Registry {
+    /// Runs the given future to completion on the current thread, registered in this registry
+    /// with the given key and instrumented with the given root [`Span`].
+    ///
+    /// This allows synchronous programs like CLI tools and tests to produce await-trees without
+    /// spawning tokio tasks. The future is driven by a minimal executor that parks the current
+    /// thread until woken, so it must not rely on a tokio runtime, unless one is entered on the
+    /// current thread, e.g., with [`tokio::runtime::Handle::enter`]. To drive the future with
+    /// another executor instead, pass the future returned by
+    /// [`TreeRoot::into_instrumented`](crate::TreeRoot::into_instrumented) to it.
+    pub fn block_on<F: Future>(
+        &self,
+        key: impl Key,
+        root_span: impl Into<Span>,
+        future: F,
+    ) -> F::Output {
+        run(self.register(key, root_span).into_instrumented(future))
+    }
+}
+
+/// Wakes the thread blocked on the future by unparking it.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs the future to completion on the current thread, parking the thread while it's pending.
+fn run<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export the await-trees of a registry in the [Trace Event Format], which can be loaded into
+//! `chrome://tracing` or [Perfetto](https://ui.perfetto.dev).
+//!
+//! [Trace Event Format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::Registry;
+
+/// Write `s` as a JSON string literal to `out`.
+fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Convert the duration to microseconds, which is the time unit of the Trace Event Format.
+fn as_micros_f64(d: Duration) -> f64 {
+    d.as_nanos() as f64 / 1000.0
+}
+
+impl Registry {
+    /// Export a snapshot of all await-trees in this registry as a JSON string in the [Trace Event
+    /// Format], which can be loaded into `chrome://tracing` or [Perfetto](https://ui.perfetto.dev).
+    ///
+    /// Each span is mapped to a complete (`"ph": "X"`) event, with `ts` derived from its start time
+    /// and `dur` from its elapsed time. All events of a registry share the same `pid`, while each
+    /// await-tree is assigned a distinct `tid` named after its key. Timestamps are relative to the
+    /// earliest span in the snapshot.
+    ///
+    /// [Trace Event Format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+    pub fn to_chrome_trace(&self) -> String {
+        let trees = self.collect_all();
+        let pid = self.id();
+        let base = trees
+            .iter()
+            .flat_map(|(_, tree)| tree.spans().map(|(_, start_time, _)| start_time))
+            .min();
+
+        let mut events = Vec::new();
+        for (tid, (key, tree)) in (1..).zip(&trees) {
+            let mut event = String::new();
+            write!(
+                event,
+                r#"{{"name":"thread_name","ph":"M","pid":{pid},"tid":{tid},"args":{{"name":"#
+            )
+            .unwrap();
+            write_json_str(&mut event, &key.to_string());
+            event.push_str("}}");
+            events.push(event);
+
+            for (span, start_time, elapsed) in tree.spans() {
+                let ts = as_micros_f64(start_time.duration_since(base.unwrap()));
+                let dur = as_micros_f64(elapsed);
+
+                let mut event = String::from(r#"{"name":"#);
+                write_json_str(&mut event, &span.to_string());
+                write!(
+                    event,
+                    r#","cat":"await-tree","ph":"X","ts":{ts},"dur":{dur},"pid":{pid},"tid":{tid}}}"#
+                )
+                .unwrap();
+                events.push(event);
+            }
+        }
+
+        format!(
+            r#"{{"traceEvents":[{}],"displayTimeUnit":"ms"}}"#,
+            events.join(",")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn test_write_json_str() {
+        let mut out = String::new();
+        write_json_str(&mut out, "a \"quoted\"\tname\\\n\u{1}");
+        assert_eq!(out, r#""a \"quoted\"\tname\\\n\u0001""#);
+    }
+
+    #[tokio::test]
+    async fn test_to_chrome_trace() {
+        let registry = Registry::new(Config::default());
+        let root = registry.register("task \"1\"", "root");
+
+        root.instrument(async {
+            let trace = registry.to_chrome_trace();
+            let trace: serde_json::Value = serde_json::from_str(&trace).unwrap();
+            let events = trace["traceEvents"].as_array().unwrap();
+            assert_eq!(events.len(), 2);
+
+            let meta = &events[0];
+            assert_eq!(meta["ph"], "M");
+            assert_eq!(meta["args"]["name"], "task \"1\"");
+
+            let span = &events[1];
+            assert_eq!(span["name"], "root");
+            assert_eq!(span["ph"], "X");
+            assert_eq!(span["ts"], 0.0);
+            assert_eq!(span["pid"], meta["pid"]);
+            assert_eq!(span["tid"], meta["tid"]);
+        })
+        .await;
+    }
+}
@@ -12,15 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Write};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, SystemTime};
 
+use flexstr::SharedStr;
 use indextree::{Arena, NodeId};
 use itertools::Itertools;
 use parking_lot::{Mutex, MutexGuard};
 
 use crate::root::current_context;
-use crate::Span;
+use crate::subscribe::{Subscribers, TreeEvent};
+use crate::time::{Instant, Rfc3339};
+use crate::{AnyKey, Config, ContextMismatchCallback, Span, SpanCloseCallback, TreeStyle};
+
+mod diff;
+mod html;
+mod newick;
+mod nodes;
+#[cfg(feature = "serde")]
+mod serde_impl;
+
+pub use diff::TreeDiff;
+pub use nodes::TreeNode;
 
 /// Node in the span tree.
 #[derive(Debug, Clone)]
@@ -29,17 +45,92 @@ struct SpanNode {
     span: Span,
 
     /// The time when this span was started, or the future was first polled.
-    start_time: coarsetime::Instant,
+    start_time: Instant,
+
+    /// The reason why this span was detached from its parent, if it's the root of a detached
+    /// subtree.
+    detach_reason: Option<DetachReason>,
+
+    /// The progress of the span in `0.0..=1.0`, set with [`set_current_progress`].
+    progress: Option<f32>,
+
+    /// Whether this node is a link to the await-tree of a child task, registered with
+    /// [`Registry::register_child`](crate::Registry::register_child), instead of a span.
+    link: bool,
+
+    /// Whether the future of this span has not been polled yet, recorded on construction with
+    /// [`InstrumentAwait::instrument_await_eager`](crate::InstrumentAwait::instrument_await_eager).
+    dormant: bool,
+
+    /// Whether the instrumented stream of this span has yielded an error, marked by
+    /// [`InstrumentTryStream`](crate::InstrumentTryStream).
+    failed: bool,
 }
 
 impl SpanNode {
-    /// Create a new node with the given value.
-    fn new(span: Span) -> Self {
+    /// Create a new node with the given value, started at the given time or now.
+    fn new(span: Span, start_time: Option<Instant>) -> Self {
         Self {
             span,
-            start_time: coarsetime::Instant::now(),
+            start_time: start_time.unwrap_or_else(Instant::now),
+            detach_reason: None,
+            progress: None,
+            link: false,
+            dormant: false,
+            failed: false,
         }
     }
+
+    /// The key for sorting sibling spans, by the order set with
+    /// [`SpanExt::order`](crate::SpanExt::order) and then the start time.
+    fn sort_key(&self) -> (Option<i32>, Instant) {
+        (self.span.order, self.start_time)
+    }
+
+    /// Get the elapsed time of this span until `now`.
+    fn elapsed_at(&self, now: Instant) -> Duration {
+        now.duration_since(self.start_time)
+    }
+}
+
+/// The reason why a subtree was detached from the tree, shown as `[Detached N: select-arm]` in the
+/// output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum DetachReason {
+    /// The parent future completed while this future is still alive, like a losing arm of a
+    /// `select`.
+    SelectArm,
+    /// The parent future was dropped before completion, like an aborted branch.
+    Aborted,
+    /// The parent future completed or was dropped while being remounted with
+    /// [`with_parent_span`](crate::with_parent_span).
+    Remount,
+}
+
+impl std::fmt::Display for DetachReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::SelectArm => "select-arm",
+            Self::Aborted => "aborted",
+            Self::Remount => "remount",
+        })
+    }
+}
+
+/// The result of [`Tree::step_in`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StepIn {
+    /// Stepped in the span.
+    Entered,
+    /// The span has been evicted from the tree.
+    Evicted,
+    /// The span is an ancestor of the current span, so it cannot be attached under it.
+    Cyclic,
 }
 
 /// The id of an await-tree context.
@@ -52,6 +143,17 @@ impl SpanNode {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) struct ContextId(pub(crate) u64);
 
+/// A handle identifying a span in the await-tree of a task, used for remounting futures under it
+/// with [`with_parent_span`](crate::with_parent_span).
+///
+/// Obtained with [`current_span_handle`] or
+/// [`Instrumented::span_handle`](crate::Instrumented::span_handle).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanHandle {
+    pub(crate) node: NodeId,
+    pub(crate) context_id: ContextId,
+}
+
 /// An await-tree for a task.
 #[derive(Debug, Clone)]
 pub struct Tree {
@@ -63,50 +165,201 @@ pub struct Tree {
 
     /// The current span node. This is the node that is currently being polled.
     current: NodeId,
+
+    /// The depth of the current span node, where the root span is at depth 1.
+    depth: usize,
+
+    /// The number of remounts in progress, i.e., [`Tree::enter_at`] not yet restored.
+    remounting: usize,
+
+    /// The time when this tree was frozen, used as the end time of all spans if set. For example,
+    /// a tree deserialized from a snapshot is frozen.
+    frozen_at: Option<Instant>,
+
+    /// The threshold of the elapsed time for a span to be considered long-running, which is
+    /// highlighted in the output.
+    warn_threshold: Duration,
+
+    /// Whether to show the category of spans in the output.
+    show_category: bool,
+
+    /// The style of the indentation of spans in the output.
+    tree_style: TreeStyle,
+
+    /// The maximum number of characters of span names in the output.
+    max_span_name_len: Option<usize>,
+
+    /// Whether to collapse the identical sibling leaf spans in the output.
+    collapse_siblings: bool,
+
+    /// The id of the tokio task that polls the root future, captured on its first poll.
+    task_id: Option<tokio::task::Id>,
+
+    /// The wall-clock time paired with an instant, for converting the start time of spans to the
+    /// wall-clock time. Set for a tree deserialized from a snapshot, whose start times are made up
+    /// on deserialization. Otherwise, the current time is used.
+    wall_clock_anchor: Option<(SystemTime, Instant)>,
 }
 
 impl std::fmt::Display for Tree {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_with(f, None)
+    }
+}
+
+/// The wrapper of [`Tree`] for formatting with the wall-clock start time of spans, returned by
+/// [`Tree::display_with_wall_clock`].
+struct WithWallClock<'a>(&'a Tree);
+
+impl std::fmt::Display for WithWallClock<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt_with(f, Some(self.0.wall_clock_anchor()))
+    }
+}
+
+impl Tree {
+    /// Format the tree, with the wall-clock start time of spans if the anchor is given.
+    fn fmt_with(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        anchor: Option<(SystemTime, Instant)>,
+    ) -> std::fmt::Result {
+        /// Format the span with its name and the enabled annotations.
+        fn fmt_span(f: &mut std::fmt::Formatter<'_>, tree: &Tree, span: &Span) -> std::fmt::Result {
+            let name = span.name();
+            match tree.max_span_name_len {
+                // Truncate on `char` boundaries.
+                Some(max_len) if name.chars().nth(max_len).is_some() => {
+                    f.write_str(&name[..name.char_indices().nth(max_len).unwrap().0])?;
+                    f.write_char('…')?;
+                }
+                _ => f.write_str(name)?,
+            }
+            if let Some(output_type) = span.output_type {
+                write!(f, " -> {output_type}")?;
+            }
+            #[cfg(feature = "location")]
+            if let Some(location) = span.location {
+                write!(f, " ({}:{})", location.file(), location.line())?;
+            }
+            if let Some(category) = span.category.filter(|_| tree.show_category) {
+                write!(f, " ({category})")?;
+            }
+            Ok(())
+        }
+
+        /// Format the node with its line prefixed by `prefix`, and the lines of its children
+        /// prefixed by `child_prefix` followed by their own branches.
+        #[allow(clippy::too_many_arguments)]
         fn fmt_node(
             f: &mut std::fmt::Formatter<'_>,
-            arena: &Arena<SpanNode>,
+            tree: &Tree,
             node: NodeId,
+            prefix: &str,
+            child_prefix: &str,
             depth: usize,
-            current: NodeId,
+            now: Instant,
+            anchor: Option<(SystemTime, Instant)>,
         ) -> std::fmt::Result {
-            f.write_str(&" ".repeat(depth * 2))?;
+            f.write_str(prefix)?;
 
-            let inner = arena[node].get();
-            f.write_str(inner.span.as_str())?;
+            let inner = tree.arena[node].get();
+            if inner.link {
+                return writeln!(f, "-> [{}]", inner.span);
+            }
+            fmt_span(f, tree, &inner.span)?;
+            if inner.dormant {
+                f.write_str(" (not yet polled)")?;
+            }
+            if inner.failed {
+                f.write_str(" (failed)")?;
+            }
 
-            let elapsed: std::time::Duration = inner.start_time.elapsed().into();
+            let elapsed = inner.elapsed_at(now);
             write!(
                 f,
-                " [{}{:.3?}]",
-                if depth > 0 && elapsed.as_secs() >= 10 {
+                " [{}{:.3?}",
+                if depth > 0 && elapsed >= tree.warn_threshold(node) {
                     "!!! "
                 } else {
                     ""
                 },
                 elapsed
             )?;
+            if let Some(progress) = inner.progress {
+                write!(f, ", {:.0}%", progress * 100.0)?;
+            }
+            if let Some(anchor) = anchor {
+                write!(
+                    f,
+                    ", since {}",
+                    Rfc3339(inner.start_time.to_system_time(anchor))
+                )?;
+            }
+            f.write_char(']')?;
 
-            if depth > 0 && node == current {
+            if depth > 0 && node == tree.current {
                 f.write_str("  <== current")?;
             }
 
             f.write_char('\n')?;
-            for child in node
-                .children(arena)
-                .sorted_by_key(|&id| arena[id].get().start_time)
-            {
-                fmt_node(f, arena, child, depth + 1, current)?;
+            let children = node
+                .children(&tree.arena)
+                .sorted_by_key(|&id| tree.arena[id].get().sort_key())
+                .collect_vec();
+            let groups = if tree.collapse_siblings {
+                children
+                    .into_iter()
+                    .group_by(|&id| tree.collapse_key(id).ok_or(id))
+                    .into_iter()
+                    .map(|(_, group)| group.collect_vec())
+                    .collect_vec()
+            } else {
+                children.into_iter().map(|id| vec![id]).collect_vec()
+            };
+            let count = groups.len();
+            for (i, group) in groups.into_iter().enumerate() {
+                let (branch, inherited) = tree.tree_style.branch(i + 1 == count);
+                if let [child] = group[..] {
+                    fmt_node(
+                        f,
+                        tree,
+                        child,
+                        &format!("{child_prefix}{branch}"),
+                        &format!("{child_prefix}{inherited}"),
+                        depth + 1,
+                        now,
+                        anchor,
+                    )?;
+                } else {
+                    let (min, max) = group
+                        .iter()
+                        .map(|&id| tree.arena[id].get().elapsed_at(now))
+                        .minmax()
+                        .into_option()
+                        .unwrap();
+                    write!(f, "{child_prefix}{branch}")?;
+                    fmt_span(f, tree, &tree.arena[group[0]].get().span)?;
+                    writeln!(
+                        f,
+                        " (x{}) [{}{:.3?}..{:.3?}]",
+                        group.len(),
+                        if max >= tree.warn_threshold(group[0]) {
+                            "!!! "
+                        } else {
+                            ""
+                        },
+                        min,
+                        max
+                    )?;
+                }
             }
 
             Ok(())
         }
 
-        fmt_node(f, &self.arena, self.root, 0, self.current)?;
+        let now = self.now();
+        fmt_node(f, self, self.root, "", "", 0, now, anchor)?;
 
         // Format all detached spans.
         for node in self.arena.iter().filter(|n| !n.is_removed()) {
@@ -115,24 +368,264 @@ impl std::fmt::Display for Tree {
                 continue;
             }
             if node.parent().is_none() {
-                writeln!(f, "[Detached {id}]")?;
-                fmt_node(f, &self.arena, id, 1, self.current)?;
+                write!(f, "[Detached {id}")?;
+                if let Some(reason) = node.get().detach_reason {
+                    write!(f, ": {reason}")?;
+                }
+                writeln!(f, "]")?;
+                let (branch, inherited) = self.tree_style.branch(true);
+                fmt_node(f, self, id, branch, inherited, 1, now, anchor)?;
             }
         }
 
         Ok(())
     }
+
+    /// Returns a wrapper for formatting the tree like the [`Display`](std::fmt::Display)
+    /// implementation, with the wall-clock start time of each span in addition to the elapsed
+    /// time, like `foo [1.006s, since 2023-11-14T22:13:20.123Z]`.
+    ///
+    /// The wall-clock time is derived from the monotonic start time of spans with the current
+    /// wall-clock time as the anchor, which helps to line up the dump with application logs. For
+    /// a tree deserialized from a snapshot, the wall-clock time recorded at serialization is used
+    /// as the anchor instead.
+    pub fn display_with_wall_clock(&self) -> impl std::fmt::Display + '_ {
+        WithWallClock(self)
+    }
+
+    /// Returns a snapshot of the tree with the elapsed time of spans frozen at now, so that the
+    /// [`Display`](std::fmt::Display) and serialized output is stable regardless of when it's
+    /// formatted.
+    ///
+    /// The tree returned by [`Registry::get`](crate::Registry::get) is live, whose elapsed time
+    /// keeps growing on each format, which suits dashboards but not reproducible logs. A tree that
+    /// is already frozen, like a deserialized one, is returned as is.
+    pub fn snapshot_elapsed_now(&self) -> Tree {
+        let mut tree = self.clone();
+        tree.freeze();
+        tree
+    }
+
+    /// Returns whether the elapsed time of spans in this tree is frozen. See
+    /// [`Tree::snapshot_elapsed_now`].
+    pub fn is_frozen(&self) -> bool {
+        self.frozen_at.is_some()
+    }
+
+    /// Returns the id of the tokio task running the await-tree, which can be used to look up the
+    /// task in `tokio-console`.
+    ///
+    /// The id is captured when the root future is first polled, so this returns `None` if the root
+    /// future has not been polled yet, or is polled outside of a tokio task, e.g., with
+    /// [`block_on`](crate::block_on). A deserialized tree also returns `None`, as the id cannot be
+    /// reconstructed from the serialized number.
+    pub fn task_id(&self) -> Option<tokio::task::Id> {
+        self.task_id
+    }
 }
 
 impl Tree {
+    /// Returns the root span of the tree.
+    pub fn root_span(&self) -> Span {
+        self.arena[self.root].get().span.clone()
+    }
+
+    /// Returns the current span of the tree, i.e., the span of the innermost future being polled,
+    /// which is the root span if no instrumented future is being polled.
+    ///
+    /// Together with [`Tree::root_span`], this is useful for building a compact view of tasks
+    /// without formatting the whole tree.
+    pub fn current_span(&self) -> Span {
+        self.arena[self.current].get().span.clone()
+    }
+
+    /// Returns the spans on the path from the root to the current span, root-first.
+    ///
+    /// This is useful for attaching the "location" of the current task to logs.
+    pub fn current_path(&self) -> Vec<Span> {
+        self.path_to(self.current)
+    }
+
+    /// Returns the spans on the path from the root of the subtree to the given node, root-first.
+    pub(crate) fn path_to(&self, node: NodeId) -> Vec<Span> {
+        let mut path = node
+            .ancestors(&self.arena)
+            .map(|id| self.arena[id].get().span.clone())
+            .collect::<Vec<_>>();
+        path.reverse();
+        path
+    }
+
+    /// Returns the spans matching the predicate with their elapsed time, including the detached
+    /// ones.
+    ///
+    /// This is useful for checking the tree in a structured way, for example, to assert that no
+    /// span named `flush` has been running for more than 5 seconds.
+    pub fn find(&self, pred: impl Fn(&Span) -> bool) -> Vec<(Span, Duration)> {
+        self.spans()
+            .filter(|(span, _, _)| pred(span))
+            .map(|(span, _, elapsed)| (span.clone(), elapsed))
+            .collect()
+    }
+
+    /// Returns whether any leaf span in this tree, including the detached ones, has been running
+    /// for at least `threshold`.
+    ///
+    /// This is the programmatic counterpart of scanning the output for the `!!!` marker, which is
+    /// useful for alerting on stuck tasks.
+    pub fn is_stalled(&self, threshold: Duration) -> bool {
+        self.leaves().any(|(_, elapsed)| elapsed >= threshold)
+    }
+
+    /// Returns the leaf spans in this tree, including the detached ones, that have been running
+    /// for at least `threshold`, with their elapsed time.
+    ///
+    /// See [`Tree::is_stalled`] for more information.
+    pub fn stalled_spans(&self, threshold: Duration) -> Vec<(Span, Duration)> {
+        self.leaves()
+            .filter(|&(_, elapsed)| elapsed >= threshold)
+            .map(|(id, elapsed)| (self.span(id).clone(), elapsed))
+            .collect()
+    }
+
+    /// Returns a new tree with only the spans matching the predicate, which is called with the
+    /// span and its elapsed time. The ancestors of a kept span are always kept to keep the tree
+    /// connected, and so is the root span.
+    ///
+    /// The current span of the new tree is the nearest kept ancestor of the current span. This is
+    /// useful for reducing the size of the tree before shipping it, like dropping fast spans.
+    pub fn prune(&self, keep: impl Fn(&Span, Duration) -> bool) -> Tree {
+        let now = self.now();
+
+        let mut kept = HashSet::new();
+        for node in self.arena.iter().filter(|n| !n.is_removed()) {
+            let id = self.arena.get_node_id(node).unwrap();
+            let value = node.get();
+            if id == self.root || keep(&value.span, value.elapsed_at(now)) {
+                for ancestor in id.ancestors(&self.arena) {
+                    if !kept.insert(ancestor) {
+                        // The rest of the ancestors have been kept.
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Copy the kept nodes into a fresh arena. As the ancestors of a kept node are also kept,
+        // its parent must have been copied in a pre-order traversal.
+        let mut arena = Arena::with_capacity(kept.len());
+        let mut mapping: HashMap<NodeId, NodeId> = HashMap::with_capacity(kept.len());
+        let roots = self
+            .arena
+            .iter()
+            .filter(|n| !n.is_removed() && n.parent().is_none())
+            .map(|n| self.arena.get_node_id(n).unwrap());
+        for id in roots.filter(|id| kept.contains(id)) {
+            for id in id.descendants(&self.arena).filter(|id| kept.contains(id)) {
+                let new_id = arena.new_node(self.arena[id].get().clone());
+                if let Some(parent) = self.arena[id].parent() {
+                    mapping[&parent].append(new_id, &mut arena);
+                }
+                mapping.insert(id, new_id);
+            }
+        }
+
+        let current = self
+            .current
+            .ancestors(&self.arena)
+            .find(|id| kept.contains(id))
+            .unwrap_or(self.root);
+        let current = mapping[&current];
+
+        Tree {
+            root: mapping[&self.root],
+            current,
+            depth: current.ancestors(&arena).count(),
+            remounting: self.remounting,
+            arena,
+            frozen_at: self.frozen_at,
+            warn_threshold: self.warn_threshold,
+            show_category: self.show_category,
+            tree_style: self.tree_style,
+            max_span_name_len: self.max_span_name_len,
+            collapse_siblings: self.collapse_siblings,
+            task_id: self.task_id,
+            wall_clock_anchor: self.wall_clock_anchor,
+        }
+    }
+
+    /// Returns the key for collapsing the node with its identical siblings in the output, or `None`
+    /// if the node should never be collapsed, i.e., it has children, or it's the current span, or
+    /// it's annotated in a way that differs among the siblings.
+    fn collapse_key(&self, id: NodeId) -> Option<&Span> {
+        let inner = self.arena[id].get();
+        let collapsible = id.children(&self.arena).next().is_none()
+            && id != self.current
+            && !inner.link
+            && !inner.dormant
+            && !inner.failed
+            && inner.progress.is_none();
+        collapsible.then_some(&inner.span)
+    }
+
+    /// Returns the wall-clock time paired with an instant, for converting the start time of spans
+    /// to the wall-clock time.
+    pub(crate) fn wall_clock_anchor(&self) -> (SystemTime, Instant) {
+        // Take the wall-clock time of now as the anchor if not set.
+        self.wall_clock_anchor
+            .unwrap_or_else(|| (SystemTime::now(), Instant::now()))
+    }
+
+    /// Returns the id and the elapsed time of all leaf span nodes in this tree, including the
+    /// detached ones. The root span is never considered as a leaf.
+    pub(crate) fn leaves(&self) -> impl Iterator<Item = (NodeId, Duration)> + '_ {
+        let now = self.now();
+        self.arena
+            .iter()
+            .filter(|n| !n.is_removed() && n.first_child().is_none())
+            .map(move |n| (self.arena.get_node_id(n).unwrap(), n.get().elapsed_at(now)))
+            .filter(|&(id, _)| id != self.root)
+    }
+
+    /// Get the span of the given node.
+    pub(crate) fn span(&self, node: NodeId) -> &Span {
+        &self.arena[node].get().span
+    }
+
+    /// Get the threshold of the elapsed time for the span of the given node to be considered
+    /// long-running, which is the one set with [`SpanExt::warn_after`](crate::SpanExt::warn_after)
+    /// if any.
+    pub(crate) fn warn_threshold(&self, node: NodeId) -> Duration {
+        self.arena[node]
+            .get()
+            .span
+            .warn_after
+            .unwrap_or(self.warn_threshold)
+    }
+
+    /// Returns the span, the start time and the elapsed time of all active span nodes in this
+    /// tree, including the detached ones.
+    pub(crate) fn spans(&self) -> impl Iterator<Item = (&Span, Instant, Duration)> + '_ {
+        let now = self.now();
+        self.arena.iter().filter(|n| !n.is_removed()).map(move |n| {
+            let node = n.get();
+            (&node.span, node.start_time, node.elapsed_at(now))
+        })
+    }
+
     /// Get the count of active span nodes in this context.
-    #[cfg(test)]
     pub(crate) fn active_node_count(&self) -> usize {
         self.arena.iter().filter(|n| !n.is_removed()).count()
     }
 
+    /// Get the count of all span nodes allocated in the arena, including the removed ones.
+    #[cfg(all(test, not(feature = "disabled")))]
+    pub(crate) fn allocated_node_count(&self) -> usize {
+        self.arena.len()
+    }
+
     /// Get the count of active detached span nodes in this context.
-    #[cfg(test)]
+    #[cfg(all(test, not(feature = "disabled")))]
     pub(crate) fn detached_node_count(&self) -> usize {
         self.arena
             .iter()
@@ -146,11 +639,13 @@ impl Tree {
 
     /// Push a new span as a child of current span, used for future firstly polled.
     ///
-    /// Returns the new current span.
-    pub(crate) fn push(&mut self, span: Span) -> NodeId {
-        let child = self.arena.new_node(SpanNode::new(span));
+    /// The span is started at `start_time` if specified, otherwise now. Returns the new current
+    /// span.
+    pub(crate) fn push(&mut self, span: Span, start_time: Option<Instant>) -> NodeId {
+        let child = self.arena.new_node(SpanNode::new(span, start_time));
         self.current.prepend(child, &mut self.arena);
         self.current = child;
+        self.depth += 1;
         child
     }
 
@@ -159,13 +654,37 @@ impl Tree {
     /// If the child is not actually a child of the current span, it means we are using a new future
     /// to poll it, so we need to detach it from the previous parent, and attach it to the current
     /// span.
-    pub(crate) fn step_in(&mut self, child: NodeId) {
+    ///
+    /// Returns [`StepIn::Evicted`] without stepping in if the child has been evicted from the
+    /// tree, which can happen to a detached span. See
+    /// [`ConfigBuilder::max_detached`](crate::ConfigBuilder::max_detached).
+    ///
+    /// Returns [`StepIn::Cyclic`] without stepping in if the current span is the child itself or
+    /// one of its descendants, which can happen if a future is remounted under its own span with
+    /// [`with_parent_span`](crate::with_parent_span).
+    pub(crate) fn step_in(&mut self, child: NodeId) -> StepIn {
+        if child.is_removed(&self.arena) {
+            return StepIn::Evicted;
+        }
         if !self.current.children(&self.arena).contains(&child) {
+            if self.current.ancestors(&self.arena).contains(&child) {
+                tracing::warn!(
+                    span = %self.arena[child].get().span,
+                    "cannot attach a span under itself or its descendant, polling it as is"
+                );
+                return StepIn::Cyclic;
+            }
             // Actually we can always call this even if `child` is already a child of `current`. But
             // checking first performs better.
             self.current.prepend(child, &mut self.arena);
+            // The start time is intentionally kept, so that the elapsed time of a remounted span
+            // covers the time it was detached.
+            self.arena[child].get_mut().detach_reason = None;
         }
+        self.arena[child].get_mut().dormant = false;
         self.current = child;
+        self.depth += 1;
+        StepIn::Entered
     }
 
     /// Pop the current span to the parent, used for future ready.
@@ -177,8 +696,9 @@ impl Tree {
         let parent = self.arena[self.current]
             .parent()
             .expect("the root node should not be popped");
-        self.remove_and_detach(self.current);
+        self.remove_and_detach(self.current, DetachReason::SelectArm);
         self.current = parent;
+        self.depth -= 1;
     }
 
     /// Step out the current span to the parent, used for future pending.
@@ -187,26 +707,155 @@ impl Tree {
             .parent()
             .expect("the root node should not be stepped out");
         self.current = parent;
+        self.depth -= 1;
     }
 
     /// Remove the current span and detach the children, used for future aborting.
     ///
     /// The children might be polled again later, and will be attached as the children of a new
-    /// span.
-    pub(crate) fn remove_and_detach(&mut self, node: NodeId) {
+    /// span. Until then, they are marked with the given reason, or [`DetachReason::Remount`] if a
+    /// remount is in progress.
+    pub(crate) fn remove_and_detach(&mut self, node: NodeId, reason: DetachReason) {
+        if node.is_removed(&self.arena) {
+            // Already evicted.
+            return;
+        }
+        let reason = if self.remounting > 0 {
+            DetachReason::Remount
+        } else {
+            reason
+        };
+        let mut child = self.arena[node].first_child();
+        while let Some(id) = child {
+            let child_node = &mut self.arena[id];
+            child_node.get_mut().detach_reason = Some(reason);
+            child = child_node.next_sibling();
+        }
         node.detach(&mut self.arena);
         // Removing detached `node` makes children detached.
         node.remove(&mut self.arena);
     }
 
+    /// Replace the name of the current span node, keeping the other attributes of the span.
+    pub(crate) fn rename_current(&mut self, name: impl AsRef<str>) {
+        self.arena[self.current].get_mut().span.rename(name);
+    }
+
+    /// Add a new node as a child of the current span node, without stepping in it.
+    fn add_child(&mut self, node: SpanNode) -> NodeId {
+        let child = self.arena.new_node(node);
+        self.current.prepend(child, &mut self.arena);
+        child
+    }
+
+    /// Add a link to the await-tree of a child task under the current span node, without stepping
+    /// in it. Returns the id of the link node.
+    pub(crate) fn add_link(&mut self, label: Span) -> NodeId {
+        self.add_child(SpanNode {
+            link: true,
+            ..SpanNode::new(label, None)
+        })
+    }
+
+    /// Add a span of a future not polled yet under the current span node, without stepping in it.
+    /// The span is stepped in with [`Tree::step_in`] when the future is first polled.
+    pub(crate) fn add_dormant(&mut self, span: Span, start_time: Option<Instant>) -> NodeId {
+        self.add_child(SpanNode {
+            dormant: true,
+            ..SpanNode::new(span, start_time)
+        })
+    }
+
+    /// Mark the span of the given node as failed, if it has not been evicted.
+    pub(crate) fn mark_failed(&mut self, node: NodeId) {
+        if !node.is_removed(&self.arena) {
+            self.arena[node].get_mut().failed = true;
+        }
+    }
+
+    /// Remove the link node added with [`Tree::add_link`], if it has not been evicted.
+    pub(crate) fn remove_link(&mut self, link: NodeId) {
+        if !link.is_removed(&self.arena) {
+            link.remove_subtree(&mut self.arena);
+        }
+    }
+
+    /// Set the progress of the current span node.
+    pub(crate) fn set_current_progress(&mut self, progress: f32) {
+        self.arena[self.current].get_mut().progress = Some(progress);
+    }
+
+    /// Remove the oldest detached subtrees, so that there are at most `max` of them.
+    ///
+    /// Returns the id and the elapsed time of all evicted span nodes.
+    pub(crate) fn evict_detached(&mut self, max: usize) -> Vec<(NodeId, Duration)> {
+        let detached = self
+            .arena
+            .iter()
+            .filter(|n| !n.is_removed() && n.parent().is_none())
+            .map(|n| self.arena.get_node_id(n).unwrap())
+            .filter(|&id| id != self.root)
+            .sorted_by_key(|&id| self.arena[id].get().start_time)
+            .collect_vec();
+
+        let evict_count = detached.len().saturating_sub(max);
+        let now = self.now();
+        let mut evicted = Vec::new();
+        for id in &detached[..evict_count] {
+            evicted.extend(
+                id.descendants(&self.arena)
+                    .map(|id| (id, self.arena[id].get().elapsed_at(now))),
+            );
+            id.remove_subtree(&mut self.arena);
+        }
+        evicted
+    }
+
+    /// Freeze the tree at the current time, so that the elapsed time of spans no longer grows.
+    pub(crate) fn freeze(&mut self) {
+        self.frozen_at.get_or_insert_with(Instant::now);
+    }
+
+    /// Move the current span to the given node, so that new spans are attached under it.
+    ///
+    /// Returns the previous current span and depth to be restored with [`Tree::restore_current`],
+    /// or `None` if the node has been removed.
+    pub(crate) fn enter_at(&mut self, node: NodeId) -> Option<(NodeId, usize)> {
+        if node.is_removed(&self.arena) {
+            return None;
+        }
+        let saved = (self.current, self.depth);
+        self.current = node;
+        self.depth = node.ancestors(&self.arena).count();
+        self.remounting += 1;
+        Some(saved)
+    }
+
+    /// Restore the current span and depth saved by [`Tree::enter_at`].
+    pub(crate) fn restore_current(&mut self, (current, depth): (NodeId, usize)) {
+        self.current = current;
+        self.depth = depth;
+        self.remounting -= 1;
+    }
+
     /// Get the current span node id.
     pub(crate) fn current(&self) -> NodeId {
         self.current
     }
+
+    /// Get the depth of the current span node, where the root span is at depth 1.
+    pub(crate) fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Get the time used as the end time of all spans, which is the current time unless the tree
+    /// is frozen.
+    fn now(&self) -> Instant {
+        self.frozen_at.unwrap_or_else(Instant::now)
+    }
 }
 
 /// The task-local await-tree context.
-#[derive(Debug)]
 pub(crate) struct TreeContext {
     /// The id of the context.
     id: ContextId,
@@ -214,26 +863,94 @@ pub(crate) struct TreeContext {
     /// Whether to include the "verbose" span in the tree.
     verbose: bool,
 
+    /// The maximum depth of the tree.
+    max_depth: Option<usize>,
+
+    /// Whether the warning of exceeding the maximum depth has been logged.
+    max_depth_warned: AtomicBool,
+
+    /// The maximum number of detached subtrees to keep.
+    max_detached: Option<usize>,
+
+    /// Whether to append the output type of instrumented futures to their spans.
+    show_output_type: bool,
+
+    /// Whether to record the spans of futures not polled yet.
+    record_unpolled: bool,
+
+    /// Whether to record the source location of instrumented futures in their spans.
+    #[cfg(feature = "location")]
+    show_location: bool,
+
+    /// Whether to check that the tree has collapsed to the root when the root future completes.
+    strict: bool,
+
+    /// The threshold of the hash for a future to be sampled, or `None` if all futures are sampled.
+    sample_threshold: Option<u64>,
+
+    /// The threshold of the elapsed time for a future to be recorded regardless of sampling.
+    warn_threshold: Duration,
+
+    /// The callback invoked when a span is closed.
+    on_span_close: Option<SpanCloseCallback>,
+
+    /// The callback invoked when a span of another context is polled or dropped in this context.
+    on_context_mismatch: Option<ContextMismatchCallback>,
+
+    /// The context of the parent task and the link node in its tree pointing to this one. Set when
+    /// registered with [`Registry::register_child`](crate::Registry::register_child), and the
+    /// link is removed when this context is dropped.
+    parent_link: OnceLock<(Weak<TreeContext>, NodeId)>,
+
+    /// The key of the await-tree and the subscribers of the registry to emit events to. Set when
+    /// registered.
+    event_sink: Option<(AnyKey, Arc<Subscribers>)>,
+
     /// The await-tree.
     tree: Mutex<Tree>,
 }
 
 impl TreeContext {
     /// Create a new context.
-    pub(crate) fn new(root_span: Span, verbose: bool) -> Self {
+    pub(crate) fn new(root_span: Span, config: &Config) -> Self {
         static ID: AtomicU64 = AtomicU64::new(0);
         let id = ID.fetch_add(1, Ordering::Relaxed);
 
         let mut arena = Arena::new();
-        let root = arena.new_node(SpanNode::new(root_span));
+        let root = arena.new_node(SpanNode::new(root_span, None));
 
         Self {
             id: ContextId(id),
-            verbose,
+            verbose: config.verbose,
+            max_depth: config.max_depth,
+            max_depth_warned: AtomicBool::new(false),
+            max_detached: config.max_detached,
+            show_output_type: config.show_output_type,
+            record_unpolled: config.record_unpolled,
+            #[cfg(feature = "location")]
+            show_location: config.show_location,
+            strict: config.strict,
+            sample_threshold: (config.sample_rate < 1.0)
+                .then(|| (config.sample_rate.max(0.0) * u64::MAX as f64) as u64),
+            warn_threshold: config.warn_threshold,
+            on_span_close: config.on_span_close.clone(),
+            on_context_mismatch: config.on_context_mismatch.clone(),
+            parent_link: OnceLock::new(),
+            event_sink: None,
             tree: Tree {
                 arena,
                 root,
                 current: root,
+                depth: 1,
+                remounting: 0,
+                frozen_at: None,
+                warn_threshold: config.warn_threshold,
+                show_category: config.show_category,
+                tree_style: config.tree_style,
+                max_span_name_len: config.max_span_name_len,
+                collapse_siblings: config.collapse_siblings,
+                task_id: None,
+                wall_clock_anchor: None,
             }
             .into(),
         }
@@ -244,6 +961,11 @@ impl TreeContext {
         self.id
     }
 
+    /// Record the id of the tokio task running the await-tree.
+    pub(crate) fn set_task_id(&self, id: tokio::task::Id) {
+        self.tree().task_id = Some(id);
+    }
+
     /// Returns the locked guard of the tree.
     pub(crate) fn tree(&self) -> MutexGuard<'_, Tree> {
         self.tree.lock()
@@ -253,6 +975,275 @@ impl TreeContext {
     pub(crate) fn verbose(&self) -> bool {
         self.verbose
     }
+
+    /// Whether the instrumented future at the given address should be recorded according to
+    /// [`ConfigBuilder::sample_rate`](crate::ConfigBuilder::sample_rate), given the time when it
+    /// was first polled.
+    ///
+    /// The decision is consistent for the same future in this context, unless the future has been
+    /// running for longer than the warning threshold, after which it's always recorded.
+    pub(crate) fn is_sampled(&self, addr: usize, first_polled: Instant) -> bool {
+        let Some(threshold) = self.sample_threshold else {
+            return true;
+        };
+        // The finalizer of SplitMix64, for spreading the bits of the context id and the address.
+        let mut x = self.id.0.wrapping_mul(0x9e3779b97f4a7c15) ^ addr as u64;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^= x >> 31;
+        x < threshold || Instant::now().duration_since(first_polled) >= self.warn_threshold
+    }
+
+    /// Whether the spans of futures not polled yet should be recorded.
+    pub(crate) fn record_unpolled(&self) -> bool {
+        self.record_unpolled
+    }
+
+    /// Whether the output type of instrumented futures should be appended to their spans.
+    pub(crate) fn show_output_type(&self) -> bool {
+        self.show_output_type
+    }
+
+    /// Whether the source location of instrumented futures should be recorded in their spans.
+    #[cfg(feature = "location")]
+    pub(crate) fn show_location(&self) -> bool {
+        self.show_location
+    }
+
+    /// Panic if the strict mode is enabled and the tree has not collapsed to the root span, which
+    /// should be called when the root future completes. See [`ConfigBuilder::strict`].
+    ///
+    /// [`ConfigBuilder::strict`]: crate::ConfigBuilder::strict
+    pub(crate) fn check_collapsed(&self) {
+        if !self.strict {
+            return;
+        }
+        let leaked = {
+            let tree = self.tree();
+            // Links to child tasks are not owned by this task, so they are not considered leaked.
+            let spans = tree
+                .arena
+                .iter()
+                .filter(|n| !n.is_removed() && !n.get().link)
+                .count();
+            let collapsed = tree.current == tree.root && spans == 1;
+            (!collapsed).then(|| tree.to_string())
+        };
+        if let Some(tree) = leaked {
+            panic!("await-tree has not collapsed to the root span on completion:\n{tree}");
+        }
+    }
+
+    /// Link this context to the current span of the parent context, shown as `-> [label]` in the
+    /// parent tree until this context is dropped.
+    pub(crate) fn link_to_parent(&self, parent: &Arc<TreeContext>, label: Span) {
+        let link = parent.tree().add_link(label);
+        let _ = self.parent_link.set((Arc::downgrade(parent), link));
+    }
+
+    /// Set the key and the subscribers to emit the events of this tree to.
+    pub(crate) fn set_event_sink(&mut self, key: AnyKey, subscribers: Arc<Subscribers>) {
+        self.event_sink = Some((key, subscribers));
+    }
+
+    /// Emit the event built with the key of the tree, if there's any subscriber.
+    fn emit(&self, event: impl FnOnce(AnyKey) -> TreeEvent) {
+        if let Some((key, subscribers)) = &self.event_sink {
+            if subscribers.is_active() {
+                subscribers.emit(event(key.clone()));
+            }
+        }
+    }
+
+    /// Whether any event would be emitted.
+    fn emits_events(&self) -> bool {
+        self.event_sink
+            .as_ref()
+            .is_some_and(|(_, subscribers)| subscribers.is_active())
+    }
+
+    /// Push a new span as a child of current span. See [`Tree::push`].
+    pub(crate) fn push(&self, span: Span, start_time: Option<Instant>) -> NodeId {
+        self.push_inner(span, start_time, false)
+    }
+
+    /// Add a span of a future not polled yet as a child of current span. See
+    /// [`Tree::add_dormant`].
+    pub(crate) fn push_dormant(&self, span: Span, start_time: Option<Instant>) -> NodeId {
+        self.push_inner(span, start_time, true)
+    }
+
+    fn push_inner(&self, span: Span, start_time: Option<Instant>, dormant: bool) -> NodeId {
+        let opened = self.emits_events().then(|| span.clone());
+        let node = if dormant {
+            self.tree().add_dormant(span, start_time)
+        } else {
+            self.tree().push(span, start_time)
+        };
+
+        if let Some(span) = opened {
+            self.emit(|key| TreeEvent::SpanOpened {
+                key,
+                node_id: node.into(),
+                span,
+            });
+        }
+        node
+    }
+
+    /// Pop the current span of the tree, then invoke the `on_span_close` callback if set.
+    ///
+    /// The callback is invoked after the lock of the tree is released.
+    pub(crate) fn pop(&self) {
+        let closed = {
+            let mut tree = self.tree();
+            let closed = (self.on_span_close.is_some() || self.emits_events()).then(|| {
+                let node = tree.arena[tree.current].get();
+                (tree.current, node.span.clone(), node.elapsed_at(tree.now()))
+            });
+            tree.pop();
+            let evicted = self.evict_detached(&mut tree);
+            (closed, evicted)
+        };
+
+        let (closed, evicted) = closed;
+        if let Some((node, span, elapsed)) = closed {
+            if let Some(callback) = &self.on_span_close {
+                callback(&span, elapsed);
+            }
+            self.emit(|key| TreeEvent::SpanClosed {
+                key,
+                node_id: node.into(),
+                elapsed,
+            });
+        }
+        self.emit_evicted(evicted);
+    }
+
+    /// Close the span of the given node entered with [`enter_span`](crate::enter_span).
+    ///
+    /// If the node is an ancestor of the current span, i.e., the guards are dropped out of order,
+    /// the spans entered after it are closed as well. If the node is not on the current path at
+    /// all, it's removed with its children detached like an aborted future.
+    pub(crate) fn exit(&self, node: NodeId) {
+        let distance = {
+            let tree = self.tree();
+            tree.current
+                .ancestors(&tree.arena)
+                .take(tree.depth - 1)
+                .position(|id| id == node)
+        };
+        match distance {
+            Some(distance) => (0..=distance).for_each(|_| self.pop()),
+            None => self.remove_and_detach(node),
+        }
+    }
+
+    /// Remove the given span and detach its children. See [`Tree::remove_and_detach`].
+    pub(crate) fn remove_and_detach(&self, node: NodeId) {
+        let closed = {
+            let mut tree = self.tree();
+            let closed = (self.emits_events() && !node.is_removed(&tree.arena))
+                .then(|| tree.arena[node].get().elapsed_at(tree.now()));
+            tree.remove_and_detach(node, DetachReason::Aborted);
+            let evicted = self.evict_detached(&mut tree);
+            (closed, evicted)
+        };
+
+        let (closed, evicted) = closed;
+        if let Some(elapsed) = closed {
+            self.emit(|key| TreeEvent::SpanClosed {
+                key,
+                node_id: node.into(),
+                elapsed,
+            });
+        }
+        self.emit_evicted(evicted);
+    }
+
+    /// Evict the oldest detached subtrees if there are more than `max_detached` of them.
+    ///
+    /// Returns the id and the elapsed time of all evicted span nodes, whose close events should be
+    /// emitted with [`TreeContext::emit_evicted`] after the lock of the tree is released.
+    fn evict_detached(&self, tree: &mut Tree) -> Vec<(NodeId, Duration)> {
+        match self.max_detached {
+            Some(max_detached) => tree.evict_detached(max_detached),
+            None => Vec::new(),
+        }
+    }
+
+    /// Emit the close events of the evicted span nodes, as they will never be closed otherwise.
+    fn emit_evicted(&self, evicted: Vec<(NodeId, Duration)>) {
+        for (node, elapsed) in evicted {
+            self.emit(|key| TreeEvent::SpanClosed {
+                key,
+                node_id: node.into(),
+                elapsed,
+            });
+        }
+    }
+
+    /// Report that the span first polled in the context `first` is polled or dropped in this
+    /// context, by logging a warning with `message` and invoking the `on_context_mismatch`
+    /// callback if set.
+    pub(crate) fn report_context_mismatch(
+        &self,
+        name: &SharedStr,
+        first: ContextId,
+        message: &str,
+    ) {
+        tracing::warn!(
+            span = %name,
+            first_task_id = first.0,
+            current_task_id = self.id.0,
+            "{message}"
+        );
+        if let Some(callback) = &self.on_context_mismatch {
+            let span = Span {
+                name: name.clone(),
+                ..Default::default()
+            };
+            callback(&span, first.0, self.id.0);
+        }
+    }
+
+    /// Whether pushing a new span would exceed the maximum depth of the tree. Logs a warning for
+    /// the first time it happens in this context.
+    pub(crate) fn exceeds_max_depth(&self) -> bool {
+        let Some(max_depth) = self.max_depth else {
+            return false;
+        };
+        let exceeds = self.tree().depth() >= max_depth;
+        if exceeds && !self.max_depth_warned.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                max_depth,
+                "await-tree exceeds the maximum depth, deeper spans will not be recorded"
+            );
+        }
+        exceeds
+    }
+}
+
+impl Drop for TreeContext {
+    fn drop(&mut self) {
+        if let Some((parent, link)) = self.parent_link.get() {
+            if let Some(parent) = parent.upgrade() {
+                parent.tree().remove_link(*link);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for TreeContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TreeContext")
+            .field("id", &self.id)
+            .field("verbose", &self.verbose)
+            .field("max_depth", &self.max_depth)
+            .field("max_detached", &self.max_detached)
+            .field("tree", &self.tree)
+            .finish_non_exhaustive()
+    }
 }
 
 /// Get the await-tree of current task. Returns `None` if we're not instrumented.
@@ -261,3 +1252,48 @@ impl TreeContext {
 pub fn current_tree() -> Option<Tree> {
     current_context().map(|c| c.tree().clone())
 }
+
+/// Get the unique id of the await-tree context of current task. Returns `None` if we're not
+/// instrumented.
+///
+/// This is cheap and useful for tagging application logs with the task they ran under, to
+/// correlate them with the warnings logged by this crate.
+pub fn current_task_id() -> Option<u64> {
+    current_context().map(|c| c.id().0)
+}
+
+/// Rename the current span of the current task, which is the span of the innermost instrumented
+/// future being polled.
+///
+/// This is useful if more context about the span is learned after it's started. Only the name is
+/// replaced, while the other attributes of the span like the category are kept. This is a no-op if
+/// we're not instrumented.
+pub fn rename_current_span(name: impl AsRef<str>) {
+    if let Some(c) = current_context() {
+        c.tree().rename_current(name);
+    }
+}
+
+/// Set the progress of the current span of the current task, which is the span of the innermost
+/// instrumented future being polled, shown like `compact [12.3s, 47%]`.
+///
+/// The progress is a fraction in `0.0..=1.0`, and is clamped into the range. This is useful for
+/// long-running operations like compaction or backfill, where the elapsed time alone does not tell
+/// how far it has gone. This is a no-op if we're not instrumented, or `progress` is NaN.
+pub fn set_current_progress(progress: f32) {
+    if progress.is_nan() {
+        return;
+    }
+    if let Some(c) = current_context() {
+        c.tree().set_current_progress(progress.clamp(0.0, 1.0));
+    }
+}
+
+/// Get the handle of the current span of the current task, which is the span of the innermost
+/// instrumented future being polled. Returns `None` if we're not instrumented.
+pub fn current_span_handle() -> Option<SpanHandle> {
+    current_context().map(|c| SpanHandle {
+        node: c.tree().current(),
+        context_id: c.id(),
+    })
+}
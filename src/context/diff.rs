@@ -0,0 +1,163 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Structural comparison of [`Tree`]s, ignoring the elapsed time of spans.
+
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+
+use super::Tree;
+use crate::Span;
+
+/// The structural difference between two [`Tree`]s, returned by [`Tree::diff`].
+///
+/// Each span is identified by its path, i.e., the spans from the root of the tree, or the root of
+/// the detached subtree, down to itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeDiff {
+    added: Vec<Vec<Span>>,
+    removed: Vec<Vec<Span>>,
+}
+
+impl TreeDiff {
+    /// Returns the paths of the spans only in the other tree.
+    pub fn added(&self) -> &[Vec<Span>] {
+        &self.added
+    }
+
+    /// Returns the paths of the spans only in this tree.
+    pub fn removed(&self) -> &[Vec<Span>] {
+        &self.removed
+    }
+
+    /// Returns whether the two trees are structurally equal.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Formats the difference like a unified diff, with a line for each added or removed span, like
+/// `+ root / select / fut`.
+impl std::fmt::Display for TreeDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (sign, paths) in [('-', &self.removed), ('+', &self.added)] {
+            for path in paths {
+                writeln!(f, "{sign} {}", path.iter().join(" / "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Tree {
+    /// Returns the count of the spans in this tree by their paths.
+    fn path_counts(&self) -> BTreeMap<Vec<Span>, usize> {
+        let mut counts = BTreeMap::new();
+        let mut path = Vec::new();
+        for node in self.nodes() {
+            path.truncate(node.depth() - 1);
+            path.push(node.span().clone());
+            *counts.entry(path.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Returns the spans added and removed in the other tree compared to this one, ignoring the
+    /// elapsed time of spans and the order of siblings.
+    ///
+    /// This is useful for finding out what has changed between two snapshots of the same task,
+    /// which is hard to tell by diffing the [`Display`](std::fmt::Display) output dominated by the
+    /// ever-changing elapsed time.
+    pub fn diff(&self, other: &Tree) -> TreeDiff {
+        let this = self.path_counts();
+        let other = other.path_counts();
+
+        let mut diff = TreeDiff::default();
+        for (path, count) in &this {
+            let other_count = other.get(path).copied().unwrap_or_default();
+            for _ in other_count..*count {
+                diff.removed.push(path.clone());
+            }
+        }
+        for (path, count) in &other {
+            let this_count = this.get(path).copied().unwrap_or_default();
+            for _ in this_count..*count {
+                diff.added.push(path.clone());
+            }
+        }
+        diff
+    }
+
+    /// Returns whether the two trees have the same spans in the same structure, ignoring the
+    /// elapsed time of spans and the order of siblings. See [`Tree::diff`].
+    pub fn structural_eq(&self, other: &Tree) -> bool {
+        self.path_counts() == other.path_counts()
+    }
+}
+
+#[cfg(all(test, not(feature = "disabled")))]
+mod tests {
+    use futures::future::{pending, select, Either};
+    use futures::FutureExt;
+
+    use crate::{current_tree, Config, InstrumentAwait, Registry};
+
+    #[tokio::test]
+    async fn test_diff() {
+        let registry = Registry::new(Config::default());
+
+        registry
+            .register((), "root")
+            .instrument(async {
+                let before = async { current_tree().unwrap() }
+                    .instrument_await("check")
+                    .await;
+                let same = async { current_tree().unwrap() }
+                    .instrument_await("check")
+                    .await;
+                assert!(before.structural_eq(&same));
+                assert!(before.diff(&same).is_empty());
+
+                let mut fut = pending::<()>().instrument_await("fut");
+                let check = async { current_tree().unwrap() }.instrument_await("check");
+                let Either::Right((after, _)) = select(&mut fut, check.boxed())
+                    .instrument_await("select")
+                    .await
+                else {
+                    unreachable!()
+                };
+
+                assert!(!before.structural_eq(&after));
+                let diff = before.diff(&after);
+                assert_eq!(diff.removed(), [vec!["root".into(), "check".into()]]);
+                assert_eq!(
+                    diff.added(),
+                    [
+                        vec!["root".into(), "select".into()],
+                        vec!["root".into(), "select".into(), "check".into()],
+                        vec!["root".into(), "select".into(), "fut".into()],
+                    ]
+                );
+                assert_eq!(
+                    diff.to_string(),
+                    "- root / check\n\
+                     + root / select\n\
+                     + root / select / check\n\
+                     + root / select / fut\n"
+                );
+            })
+            .await;
+    }
+}
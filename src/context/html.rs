@@ -0,0 +1,93 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rendering of [`Tree`] in HTML, used by [`Registry::to_html`](crate::Registry::to_html).
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use indextree::NodeId;
+use itertools::Itertools;
+
+use super::Tree;
+use crate::html::escape;
+use crate::time::Instant;
+
+impl Tree {
+    /// Get the elapsed time of the root span.
+    pub(crate) fn root_elapsed(&self) -> Duration {
+        self.arena[self.root].get().elapsed_at(self.now())
+    }
+
+    /// Write the tree as nested `<details>` elements to `out`.
+    ///
+    /// Spans are highlighted in the same way as the `Display` implementation.
+    pub(crate) fn write_html(&self, out: &mut String) {
+        fn write_node(tree: &Tree, out: &mut String, node: NodeId, is_root: bool, now: Instant) {
+            let inner = tree.arena[node].get();
+            let elapsed = inner.elapsed_at(now);
+
+            let mut class = vec!["span"];
+            if !is_root && elapsed >= tree.warn_threshold(node) {
+                class.push("long");
+            }
+            if !is_root && node == tree.current {
+                class.push("current");
+            }
+
+            let children = node
+                .children(&tree.arena)
+                .sorted_by_key(|&id| tree.arena[id].get().sort_key())
+                .collect_vec();
+
+            let summary = format!(
+                r#"<span class="{}">{}</span> <span class="elapsed">[{:.3?}]</span>"#,
+                class.join(" "),
+                escape(&inner.span.to_string()),
+                elapsed
+            );
+            if children.is_empty() {
+                write!(out, r#"<div class="leaf">{summary}</div>"#).unwrap();
+            } else {
+                write!(out, "<details open><summary>{summary}</summary>").unwrap();
+                for child in children {
+                    write_node(tree, out, child, false, now);
+                }
+                out.push_str("</details>");
+            }
+        }
+
+        let now = self.now();
+        write_node(self, out, self.root, true, now);
+
+        // Write all detached spans.
+        for node in self.arena.iter().filter(|n| !n.is_removed()) {
+            let id = self.arena.get_node_id(node).unwrap();
+            if id != self.root && node.parent().is_none() {
+                let reason = node
+                    .get()
+                    .detach_reason
+                    .map(|reason| format!(": {reason}"))
+                    .unwrap_or_default();
+                write!(
+                    out,
+                    r#"<details open><summary class="detached">[Detached {id}{reason}]</summary>"#
+                )
+                .unwrap();
+                write_node(self, out, id, false, now);
+                out.push_str("</details>");
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rendering of [`Tree`] in the [Newick format], which can be read by phylogenetic tree tools.
+//!
+//! [Newick format]: https://en.wikipedia.org/wiki/Newick_format
+
+use std::fmt::Write;
+
+use indextree::NodeId;
+use itertools::Itertools;
+
+use super::Tree;
+use crate::time::Instant;
+
+/// Write `name` as a Newick label to `out`, quoted if it contains any character with special
+/// meaning in the format.
+fn write_label(out: &mut String, name: &str) {
+    let needs_quote = name
+        .chars()
+        .any(|c| c.is_whitespace() || "()[]':;,".contains(c));
+    if needs_quote {
+        out.push('\'');
+        out.push_str(&name.replace('\'', "''"));
+        out.push('\'');
+    } else {
+        out.push_str(name);
+    }
+}
+
+impl Tree {
+    /// Render the tree in the [Newick format], like `(child1:0.100,child2:0.200)root:0.300;`,
+    /// where the branch length of each span is its elapsed time in seconds.
+    ///
+    /// Span names with special characters are quoted. As the format can only describe a single
+    /// tree, the detached spans are not included.
+    ///
+    /// [Newick format]: https://en.wikipedia.org/wiki/Newick_format
+    pub fn to_newick(&self) -> String {
+        fn write_node(tree: &Tree, out: &mut String, node: NodeId, now: Instant) {
+            let children = node
+                .children(&tree.arena)
+                .sorted_by_key(|&id| tree.arena[id].get().sort_key())
+                .collect_vec();
+            if !children.is_empty() {
+                out.push('(');
+                for (i, child) in children.into_iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_node(tree, out, child, now);
+                }
+                out.push(')');
+            }
+
+            let inner = tree.arena[node].get();
+            write_label(out, &inner.span.to_string());
+            write!(out, ":{:.3}", inner.elapsed_at(now).as_secs_f64()).unwrap();
+        }
+
+        let mut out = String::new();
+        write_node(self, &mut out, self.root, self.now());
+        out.push(';');
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use indextree::Arena;
+
+    use super::*;
+    use crate::context::SpanNode;
+    use crate::Config;
+
+    #[test]
+    fn test_to_newick() {
+        let now = Instant::now();
+        let mut arena = Arena::new();
+        let mut new_node = |name: &str, secs| {
+            arena.new_node(SpanNode::new(
+                name.into(),
+                now.checked_sub(Duration::from_secs(secs)),
+            ))
+        };
+        let root = new_node("root", 3);
+        let a = new_node("it's (a)", 2);
+        let b = new_node("b", 1);
+        let c = new_node("c", 1);
+        // Detached spans are not included.
+        new_node("detached", 1);
+        root.append(a, &mut arena);
+        root.append(b, &mut arena);
+        a.append(c, &mut arena);
+
+        let tree = Tree {
+            arena,
+            root,
+            current: c,
+            depth: 3,
+            remounting: 0,
+            frozen_at: Some(now),
+            warn_threshold: Config::default().warn_threshold,
+            show_category: false,
+            tree_style: Default::default(),
+            max_span_name_len: None,
+            collapse_siblings: false,
+            task_id: None,
+            wall_clock_anchor: None,
+        };
+        assert_eq!(
+            tree.to_newick(),
+            "((c:1.000)'it''s (a)':2.000,b:1.000)root:3.000;"
+        );
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Public traversal of the span nodes in a [`Tree`], for writing custom exporters.
+
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use indextree::NodeId;
+use itertools::Itertools;
+
+use super::{DetachReason, Tree};
+use crate::time::Instant;
+use crate::Span;
+
+/// A span node in a [`Tree`], yielded by [`Tree::nodes`] and [`Tree::nodes_bfs`].
+#[derive(Clone, Copy)]
+pub struct TreeNode<'a> {
+    tree: &'a Tree,
+    id: NodeId,
+    depth: usize,
+    /// The time point to calculate the elapsed time, shared by all nodes of a traversal.
+    now: Instant,
+}
+
+impl<'a> TreeNode<'a> {
+    /// Returns the span of the node.
+    pub fn span(&self) -> &'a Span {
+        &self.tree.arena[self.id].get().span
+    }
+
+    /// Returns the elapsed time of the span.
+    pub fn elapsed(&self) -> Duration {
+        self.tree.arena[self.id].get().elapsed_at(self.now)
+    }
+
+    /// Returns the wall-clock time when the span was started, or the future was first polled.
+    ///
+    /// The start time is kept when the span is detached and remounted, so the elapsed time always
+    /// covers the whole lifetime of the future. See
+    /// [`Tree::display_with_wall_clock`] for how the wall-clock time is derived.
+    pub fn start_time(&self) -> SystemTime {
+        let anchor = self.tree.wall_clock_anchor();
+        self.tree.arena[self.id]
+            .get()
+            .start_time
+            .to_system_time(anchor)
+    }
+
+    /// Returns the depth of the node, where the root span, or the root of a detached subtree, is
+    /// at depth 1.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns whether the node is the current span of the tree, i.e., the span of the innermost
+    /// future being polled.
+    pub fn is_current(&self) -> bool {
+        self.id == self.tree.current
+    }
+
+    /// Returns whether the node is in a detached subtree.
+    pub fn is_detached(&self) -> bool {
+        self.id.ancestors(&self.tree.arena).last() != Some(self.tree.root)
+    }
+
+    /// Returns whether the future of the span has been polled. Only the spans recorded with
+    /// [`InstrumentAwait::instrument_await_eager`](crate::InstrumentAwait::instrument_await_eager)
+    /// can be not polled yet.
+    pub fn is_polled(&self) -> bool {
+        !self.tree.arena[self.id].get().dormant
+    }
+
+    /// Returns whether the stream of the span has yielded an error. Only the spans of streams
+    /// instrumented with [`InstrumentTryStream`](crate::InstrumentTryStream) can be failed.
+    pub fn is_failed(&self) -> bool {
+        self.tree.arena[self.id].get().failed
+    }
+
+    /// Returns the progress of the span in `0.0..=1.0`, if set with
+    /// [`set_current_progress`](crate::set_current_progress).
+    pub fn progress(&self) -> Option<f32> {
+        self.tree.arena[self.id].get().progress
+    }
+
+    /// Returns the reason why the node was detached, if it's the root of a detached subtree.
+    pub fn detach_reason(&self) -> Option<DetachReason> {
+        self.tree.arena[self.id].get().detach_reason
+    }
+
+    /// Returns the children of the node, in the same order as the [`Display`](std::fmt::Display)
+    /// output of the tree.
+    pub fn children(&self) -> impl Iterator<Item = TreeNode<'a>> + 'a {
+        let Self {
+            tree, depth, now, ..
+        } = *self;
+        self.id
+            .children(&tree.arena)
+            .sorted_by_key(|&id| tree.arena[id].get().sort_key())
+            .map(move |id| TreeNode {
+                tree,
+                id,
+                depth: depth + 1,
+                now,
+            })
+    }
+}
+
+impl std::fmt::Debug for TreeNode<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TreeNode")
+            .field("span", self.span())
+            .field("elapsed", &self.elapsed())
+            .field("depth", &self.depth)
+            .field("is_current", &self.is_current())
+            .finish()
+    }
+}
+
+impl Tree {
+    /// Returns the root span node, followed by the roots of the detached subtrees.
+    fn root_nodes(&self) -> impl Iterator<Item = TreeNode<'_>> + '_ {
+        let now = self.now();
+        let detached = self
+            .arena
+            .iter()
+            .filter(|n| !n.is_removed() && n.parent().is_none())
+            .map(|n| self.arena.get_node_id(n).unwrap())
+            .filter(|&id| id != self.root);
+
+        std::iter::once(self.root)
+            .chain(detached)
+            .map(move |id| TreeNode {
+                tree: self,
+                id,
+                depth: 1,
+                now,
+            })
+    }
+
+    /// Returns an iterator over all span nodes in the tree in depth-first pre-order, which is the
+    /// same order as the [`Display`](std::fmt::Display) output.
+    ///
+    /// The nodes of the detached subtrees are yielded after the ones of the root span. Use
+    /// [`TreeNode::is_detached`] to tell them apart.
+    pub fn nodes(&self) -> impl Iterator<Item = TreeNode<'_>> + '_ {
+        let mut stack = self.root_nodes().collect_vec();
+        stack.reverse();
+        std::iter::from_fn(move || {
+            let node = stack.pop()?;
+            let len = stack.len();
+            stack.extend(node.children());
+            stack[len..].reverse();
+            Some(node)
+        })
+    }
+
+    /// Returns an iterator over all span nodes in the tree in breadth-first order.
+    ///
+    /// Like [`Tree::nodes`], the nodes of the detached subtrees are yielded after the ones of the
+    /// root span.
+    pub fn nodes_bfs(&self) -> impl Iterator<Item = TreeNode<'_>> + '_ {
+        self.root_nodes().flat_map(|root| {
+            let mut queue = VecDeque::from([root]);
+            std::iter::from_fn(move || {
+                let node = queue.pop_front()?;
+                queue.extend(node.children());
+                Some(node)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indextree::Arena;
+
+    use super::*;
+    use crate::context::SpanNode;
+    use crate::Config;
+
+    #[test]
+    fn test_nodes() {
+        let now = Instant::now();
+        let mut arena = Arena::new();
+        let mut new_node = |name: &str, secs| {
+            arena.new_node(SpanNode::new(
+                name.into(),
+                now.checked_sub(Duration::from_secs(secs)),
+            ))
+        };
+        let root = new_node("root", 4);
+        let a = new_node("a", 3);
+        let b = new_node("b", 2);
+        let c = new_node("c", 1);
+        let d = new_node("detached", 1);
+        let e = new_node("e", 1);
+        root.append(b, &mut arena);
+        root.append(a, &mut arena);
+        a.append(c, &mut arena);
+        d.append(e, &mut arena);
+
+        let tree = Tree {
+            arena,
+            root,
+            current: c,
+            depth: 3,
+            remounting: 0,
+            frozen_at: Some(now),
+            warn_threshold: Config::default().warn_threshold,
+            show_category: false,
+            tree_style: Default::default(),
+            max_span_name_len: None,
+            collapse_siblings: false,
+            task_id: None,
+            wall_clock_anchor: None,
+        };
+
+        let dfs = tree
+            .nodes()
+            .map(|n| (n.span().name(), n.depth(), n.is_detached()))
+            .collect_vec();
+        assert_eq!(
+            dfs,
+            [
+                ("root", 1, false),
+                ("a", 2, false),
+                ("c", 3, false),
+                ("b", 2, false),
+                ("detached", 1, true),
+                ("e", 2, true),
+            ]
+        );
+
+        let bfs = tree.nodes_bfs().map(|n| n.span().name()).collect_vec();
+        assert_eq!(bfs, ["root", "a", "b", "c", "detached", "e"]);
+
+        let current = tree.nodes().filter(|n| n.is_current()).collect_vec();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].span().name(), "c");
+        assert_eq!(current[0].elapsed(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serialization and deserialization of [`Tree`].
+//!
+//! The format is as follows, where `id` identifies the node within the snapshot to be referred to
+//! by `current`, `captured_at_ns` is the wall-clock time of the snapshot in nanoseconds since the
+//! Unix epoch, `user_id` is set with [`SpanExt::with_id`](crate::SpanExt::with_id), `progress` is
+//! set with [`set_current_progress`](crate::set_current_progress), `dormant` is whether the future
+//! of the span recorded with
+//! [`InstrumentAwait::instrument_await_eager`](crate::InstrumentAwait::instrument_await_eager) has
+//! not been polled yet, `failed` is whether the stream
+//! of the span has yielded an error, marked by [`InstrumentTryStream`](crate::InstrumentTryStream),
+//! `detached` are the detached subtrees with the reason why they were detached, and `task_id` is
+//! the id of the tokio task running the tree, as returned by [`Tree::task_id`]:
+//!
+//! ```json
+//! {
+//!   "current": 2,
+//!   "captured_at_ns": 1700000000123000000,
+//!   "tree": {
+//!     "id": 1,
+//!     "span": "root",
+//!     "elapsed_ns": 1000000,
+//!     "user_id": null,
+//!     "progress": null,
+//!     "dormant": false,
+//!     "failed": false,
+//!     "children": [
+//!       {
+//!         "id": 2,
+//!         "span": "child",
+//!         "elapsed_ns": 500000,
+//!         "user_id": 42,
+//!         "progress": 0.47,
+//!         "dormant": false,
+//!         "failed": false,
+//!         "children": []
+//!       }
+//!     ]
+//!   },
+//!   "detached": [],
+//!   "task_id": 42
+//! }
+//! ```
+//!
+//! The implementation does not rely on self-describing formats, so compact binary formats like
+//! `bincode` and `postcard` are also supported.
+//!
+//! The task id is not restored on deserialization, as a [`tokio::task::Id`] cannot be constructed
+//! from the number.
+//!
+//! The ids are not preserved on deserialization, but remapped to consecutive ones in the order of
+//! the original ids.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use indextree::{Arena, NodeId};
+use itertools::Itertools;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{DetachReason, SpanNode, Tree};
+use crate::time::{saturating_nanos, Instant, MAX_ELAPSED};
+use crate::{Config, Span};
+
+#[derive(Serialize, Deserialize)]
+struct SpanNodeRepr {
+    id: usize,
+    span: Span,
+    elapsed_ns: u64,
+    user_id: Option<u64>,
+    progress: Option<f32>,
+    #[serde(default)]
+    dormant: bool,
+    #[serde(default)]
+    failed: bool,
+    children: Vec<SpanNodeRepr>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DetachedRepr {
+    reason: Option<DetachReason>,
+    tree: SpanNodeRepr,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TreeRepr {
+    current: usize,
+    captured_at_ns: u64,
+    tree: SpanNodeRepr,
+    detached: Vec<DetachedRepr>,
+    task_id: Option<u64>,
+}
+
+impl Tree {
+    fn to_repr(&self) -> TreeRepr {
+        let now = self.now();
+
+        let node_repr = |id: NodeId| {
+            fn build(arena: &Arena<SpanNode>, id: NodeId, now: Instant) -> SpanNodeRepr {
+                let node = arena[id].get();
+                let elapsed = node.elapsed_at(now);
+                SpanNodeRepr {
+                    id: id.into(),
+                    span: node.span.clone(),
+                    elapsed_ns: saturating_nanos(elapsed),
+                    user_id: node.span.user_id(),
+                    progress: node.progress,
+                    dormant: node.dormant,
+                    failed: node.failed,
+                    children: id
+                        .children(arena)
+                        .sorted_by_key(|&id| arena[id].get().sort_key())
+                        .map(|id| build(arena, id, now))
+                        .collect(),
+                }
+            }
+            build(&self.arena, id, now)
+        };
+
+        let detached = self
+            .arena
+            .iter()
+            .filter(|n| !n.is_removed() && n.parent().is_none())
+            .map(|n| self.arena.get_node_id(n).unwrap())
+            .filter(|&id| id != self.root)
+            .map(|id| DetachedRepr {
+                reason: self.arena[id].get().detach_reason,
+                tree: node_repr(id),
+            })
+            .collect();
+
+        let captured_at = now.to_system_time(self.wall_clock_anchor());
+        let captured_at_ns = saturating_nanos(
+            captured_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default(),
+        );
+
+        TreeRepr {
+            current: self.current.into(),
+            captured_at_ns,
+            tree: node_repr(self.root),
+            detached,
+            // The id is only exposed as a number through its `Display` implementation.
+            task_id: self.task_id.and_then(|id| id.to_string().parse().ok()),
+        }
+    }
+
+    fn from_repr(repr: TreeRepr) -> Result<Self, String> {
+        // Flatten the nodes, and collect the edges in the order of serialization.
+        let mut nodes = HashMap::new();
+        let mut edges = Vec::new();
+        let mut detach_reasons = Vec::new();
+        let detached = repr.detached.into_iter().map(|d| {
+            detach_reasons.push((d.tree.id, d.reason));
+            d.tree
+        });
+        let mut stack = std::iter::once(repr.tree)
+            .chain(detached)
+            .rev()
+            .map(|node| (node, None))
+            .collect_vec();
+        let root = stack.last().unwrap().0.id;
+        while let Some((node, parent)) = stack.pop() {
+            let SpanNodeRepr {
+                id,
+                span,
+                elapsed_ns,
+                user_id,
+                progress,
+                dormant,
+                failed,
+                children,
+            } = node;
+            let span = Span { user_id, ..span };
+            // Saturate the elapsed time so that the start time can be represented, instead of
+            // falling back to zero for a span that looks ancient due to a clock bug.
+            let elapsed = Duration::from_nanos(elapsed_ns).min(MAX_ELAPSED);
+            if id == 0 {
+                return Err("node id must be non-zero".to_owned());
+            }
+            let node = SpanNode {
+                progress: progress.filter(|p| (0.0..=1.0).contains(p)),
+                dormant,
+                failed,
+                ..SpanNode::new(span, None)
+            };
+            if nodes.insert(id, (elapsed, node)).is_some() {
+                return Err(format!("duplicate node id {id}"));
+            }
+            if let Some(parent) = parent {
+                edges.push((parent, id));
+            }
+            stack.extend(children.into_iter().rev().map(|child| (child, Some(id))));
+        }
+        if !nodes.contains_key(&repr.current) {
+            return Err(format!("current node {} does not exist", repr.current));
+        }
+
+        // Pick a time point late enough so that the start time of all spans can be represented.
+        let max_elapsed = nodes.values().map(|(e, _)| *e).max().unwrap_or_default();
+        let now = Instant::now();
+        let frozen_at = now.checked_add(max_elapsed).unwrap_or(now);
+
+        // Allocate the nodes in the order of ids, remapped densely so that sparse or huge ids in an
+        // untrusted input do not blow up the arena.
+        let mut arena = Arena::with_capacity(nodes.len());
+        let mut node_ids = HashMap::with_capacity(nodes.len());
+        for (id, (elapsed, mut node)) in nodes.into_iter().sorted_by_key(|(id, _)| *id) {
+            node.start_time = frozen_at.checked_sub(elapsed).unwrap_or(now);
+            node_ids.insert(id, arena.new_node(node));
+        }
+        for (parent, child) in edges {
+            node_ids[&parent].append(node_ids[&child], &mut arena);
+        }
+        for (id, reason) in detach_reasons {
+            arena[node_ids[&id]].get_mut().detach_reason = reason;
+        }
+
+        let root = node_ids[&root];
+        let current = node_ids[&repr.current];
+        let depth = current.ancestors(&arena).count();
+
+        Ok(Self {
+            arena,
+            root,
+            current,
+            depth,
+            remounting: 0,
+            frozen_at: Some(frozen_at),
+            warn_threshold: Config::default().warn_threshold,
+            show_category: Config::default().show_category,
+            tree_style: Config::default().tree_style,
+            max_span_name_len: Config::default().max_span_name_len,
+            collapse_siblings: Config::default().collapse_siblings,
+            task_id: None,
+            wall_clock_anchor: Some((
+                SystemTime::UNIX_EPOCH + Duration::from_nanos(repr.captured_at_ns),
+                frozen_at,
+            )),
+        })
+    }
+}
+
+impl Serialize for Tree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_repr().serialize(serializer)
+    }
+}
+
+/// Deserialize a [`Tree`] from the format produced by its [`Serialize`] implementation.
+///
+/// The deserialized tree is frozen: the elapsed time of each span is fixed to the serialized
+/// value, instead of growing over time.
+impl<'de> Deserialize<'de> for Tree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = TreeRepr::deserialize(deserializer)?;
+        Self::from_repr(repr).map_err(D::Error::custom)
+    }
+}
@@ -13,55 +13,195 @@
 // limitations under the License.
 
 use std::future::Future;
+#[cfg(feature = "location")]
+use std::panic::Location;
 use std::pin::Pin;
 use std::task::Poll;
 
+use flexstr::SharedStr;
 use indextree::NodeId;
 use pin_project::{pin_project, pinned_drop};
 
-use crate::context::ContextId;
+use crate::context::{ContextId, SpanHandle, StepIn};
+use crate::registry::any_registry_created;
 use crate::root::current_context;
+use crate::time::Instant;
 use crate::Span;
 
-enum State {
+enum State<G> {
     Initial(Span),
+    /// The span is lazily built with the closure on the first poll.
+    Lazy(G),
     Polled {
         this_node: NodeId,
         this_context_id: ContextId,
+        /// The name of the span, kept for reporting the context mismatch.
+        name: SharedStr,
     },
     Ready,
-    /// This span is disabled due to `verbose` configuration.
+    /// This span is disabled due to `verbose` configuration, exceeding the maximum depth, being
+    /// evicted as a detached span, or the `disabled` feature.
     Disabled,
 }
 
 /// The future for [`InstrumentAwait`][ia].
 ///
+/// The type parameter `G` is the closure for building the span lazily, which is only used by
+/// [`InstrumentAwait::instrument_await_with`][iaw].
+///
 /// [ia]: crate::InstrumentAwait
+/// [iaw]: crate::InstrumentAwait::instrument_await_with
 #[pin_project(PinnedDrop)]
-pub struct Instrumented<F: Future, const VERBOSE: bool> {
+pub struct Instrumented<F: Future, const VERBOSE: bool, G = fn() -> Span> {
     #[pin]
     inner: F,
-    state: State,
+    state: State<G>,
+    /// The start time of the span, which is the time of the first poll unless set explicitly.
+    start_time: Option<Instant>,
+    /// The source location where the future is instrumented.
+    #[cfg(feature = "location")]
+    location: &'static Location<'static>,
+    /// The `tracing` span mirroring this span, entered on every poll.
+    #[cfg(feature = "tracing")]
+    tracing_span: tracing::Span,
 }
 
 impl<F: Future, const VERBOSE: bool> Instrumented<F, VERBOSE> {
-    pub(crate) fn new(inner: F, span: Span) -> Self {
+    #[track_caller]
+    pub(crate) fn new(inner: F, span: impl Into<Span>) -> Self {
         Self {
             inner,
-            state: State::Initial(span),
+            // Drop the span without converting it if the instrumentation is stripped.
+            state: if cfg!(feature = "disabled") {
+                State::Disabled
+            } else {
+                State::Initial(span.into())
+            },
+            start_time: None,
+            #[cfg(feature = "location")]
+            location: Location::caller(),
+            #[cfg(feature = "tracing")]
+            tracing_span: tracing::Span::none(),
         }
     }
 }
 
-impl<F: Future, const VERBOSE: bool> Future for Instrumented<F, VERBOSE> {
+impl<F: Future, const VERBOSE: bool, G> Instrumented<F, VERBOSE, G> {
+    /// Returns the handle of the span of this future in the await-tree, if it has been polled and
+    /// is not ready yet.
+    ///
+    /// The handle can be used to remount other futures under this span with
+    /// [`with_parent_span`](crate::with_parent_span).
+    pub fn span_handle(&self) -> Option<SpanHandle> {
+        match self.state {
+            State::Polled {
+                this_node,
+                this_context_id,
+                ..
+            } => Some(SpanHandle {
+                node: this_node,
+                context_id: this_context_id,
+            }),
+            _ => None,
+        }
+    }
+
+    #[track_caller]
+    pub(crate) fn new_lazy(inner: F, span_fn: G) -> Self {
+        Self {
+            inner,
+            state: if cfg!(feature = "disabled") {
+                State::Disabled
+            } else {
+                State::Lazy(span_fn)
+            },
+            start_time: None,
+            #[cfg(feature = "location")]
+            location: Location::caller(),
+            #[cfg(feature = "tracing")]
+            tracing_span: tracing::Span::none(),
+        }
+    }
+
+    /// Disable the span, so that the future is polled as if it's not instrumented.
+    pub(crate) fn disabled(mut self) -> Self {
+        self.state = State::Disabled;
+        self
+    }
+
+    /// Record the span in the current context right away if
+    /// [`ConfigBuilder::record_unpolled`](crate::ConfigBuilder::record_unpolled) is set, as a
+    /// dormant span stepped in when the future is first polled.
+    pub(crate) fn eager(mut self) -> Self {
+        if cfg!(feature = "disabled") || !any_registry_created() {
+            return self;
+        }
+        let Some(c) = current_context() else {
+            return self;
+        };
+        if !c.record_unpolled() || (!c.verbose() && VERBOSE) || c.exceeds_max_depth() {
+            return self;
+        }
+        let span = match std::mem::replace(&mut self.state, State::Disabled) {
+            State::Initial(span) => span,
+            state => {
+                self.state = state;
+                return self;
+            }
+        };
+        let span = if c.show_output_type() {
+            span.with_output_type::<F::Output>()
+        } else {
+            span
+        };
+        #[cfg(feature = "location")]
+        let span = if c.show_location() {
+            Span {
+                location: Some(self.location),
+                ..span
+            }
+        } else {
+            span
+        };
+        #[cfg(feature = "tracing")]
+        {
+            self.tracing_span = tracing::info_span!("await_tree", span = %span, verbose = VERBOSE);
+        }
+        let name = span.name.clone();
+        let node = c.push_dormant(span, self.start_time);
+        self.state = State::Polled {
+            this_node: node,
+            this_context_id: c.id(),
+            name,
+        };
+        self
+    }
+
+    pub(crate) fn with_start_time(mut self, start_time: Instant) -> Self {
+        self.start_time = Some(start_time);
+        self
+    }
+}
+
+impl<F: Future, const VERBOSE: bool, G> Future for Instrumented<F, VERBOSE, G>
+where
+    G: FnOnce() -> Span,
+{
     type Output = F::Output;
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        // The address is stable since the future is pinned, used for sampling.
+        let addr = &*self as *const Self as usize;
         let this = self.project();
+        if cfg!(feature = "disabled") || !any_registry_created() {
+            // Fast path: the instrumentation is stripped, or there's no context to record the span
+            // in.
+            return this.inner.poll(cx);
+        }
         let context = current_context();
 
         let (context, this_node) = match this.state {
-            State::Initial(span) => {
+            State::Initial(_) | State::Lazy(_) => {
                 match context {
                     Some(c) => {
                         if !c.verbose() && VERBOSE {
@@ -70,11 +210,49 @@ impl<F: Future, const VERBOSE: bool> Future for Instrumented<F, VERBOSE> {
                             *this.state = State::Disabled;
                             return this.inner.poll(cx);
                         }
+                        if c.exceeds_max_depth() {
+                            // The tracing for this span is disabled to bound the size of the tree.
+                            *this.state = State::Disabled;
+                            return this.inner.poll(cx);
+                        }
+                        let first_polled = *this.start_time.get_or_insert_with(Instant::now);
+                        if !c.is_sampled(addr, first_polled) {
+                            // Not sampled for now. Keep the state to record the span if it turns
+                            // out to be long-running.
+                            return this.inner.poll(cx);
+                        }
+                        // Materialize the span outside the lock of the tree.
+                        let span = match std::mem::replace(this.state, State::Disabled) {
+                            State::Initial(span) => span,
+                            State::Lazy(span_fn) => span_fn(),
+                            _ => unreachable!(),
+                        };
+                        let span = if c.show_output_type() {
+                            span.with_output_type::<F::Output>()
+                        } else {
+                            span
+                        };
+                        #[cfg(feature = "location")]
+                        let span = if c.show_location() {
+                            Span {
+                                location: Some(*this.location),
+                                ..span
+                            }
+                        } else {
+                            span
+                        };
+                        #[cfg(feature = "tracing")]
+                        {
+                            *this.tracing_span =
+                                tracing::info_span!("await_tree", span = %span, verbose = VERBOSE);
+                        }
                         // First polled, push a new span to the context.
-                        let node = c.tree().push(std::mem::take(span));
+                        let name = span.name.clone();
+                        let node = c.push(span, *this.start_time);
                         *this.state = State::Polled {
                             this_node: node,
                             this_context_id: c.id(),
+                            name,
                         };
                         (c, node)
                     }
@@ -85,42 +263,68 @@ impl<F: Future, const VERBOSE: bool> Future for Instrumented<F, VERBOSE> {
             State::Polled {
                 this_node,
                 this_context_id: this_context,
+                name,
             } => {
                 match context {
                     // Context correct
                     Some(c) if c.id() == *this_context => {
                         // Polled before, just step in.
-                        c.tree().step_in(*this_node);
+                        let step_in = c.tree().step_in(*this_node);
+                        match step_in {
+                            StepIn::Entered => {}
+                            StepIn::Evicted => {
+                                *this.state = State::Disabled;
+                                return this.inner.poll(cx);
+                            }
+                            StepIn::Cyclic => return this.inner.poll(cx),
+                        }
                         (c, *this_node)
                     }
                     // Context changed
-                    Some(_) => {
-                        tracing::warn!(
-                            "future polled in a different context as it was first polled"
+                    Some(c) => {
+                        c.report_context_mismatch(
+                            name,
+                            *this_context,
+                            "future polled in a different context as it was first polled",
                         );
                         return this.inner.poll(cx);
                     }
                     // Out of context
                     None => {
                         tracing::warn!(
+                            span = %name,
+                            first_task_id = this_context.0,
                             "future polled not in a context, while it was when first polled"
                         );
                         return this.inner.poll(cx);
                     }
                 }
             }
-            State::Ready => unreachable!("the instrumented future should always be fused"),
+            // Polled after completion. The span has been closed, so just delegate to the inner
+            // future, whose contract decides what happens.
+            State::Ready => return this.inner.poll(cx),
             State::Disabled => return this.inner.poll(cx),
         };
 
         // The current node must be the this_node.
         debug_assert_eq!(this_node, context.tree().current());
 
-        match this.inner.poll(cx) {
+        let poll = {
+            #[cfg(feature = "tracing")]
+            let _entered = this.tracing_span.enter();
+            this.inner.poll(cx)
+        };
+
+        match poll {
             // The future is ready, clean-up this span by popping from the context.
             Poll::Ready(output) => {
-                context.tree().pop();
+                context.pop();
                 *this.state = State::Ready;
+                #[cfg(feature = "tracing")]
+                {
+                    // Close the `tracing` span as well.
+                    *this.tracing_span = tracing::Span::none();
+                }
                 Poll::Ready(output)
             }
             // Still pending, just step out.
@@ -133,7 +337,7 @@ impl<F: Future, const VERBOSE: bool> Future for Instrumented<F, VERBOSE> {
 }
 
 #[pinned_drop]
-impl<F: Future, const VERBOSE: bool> PinnedDrop for Instrumented<F, VERBOSE> {
+impl<F: Future, const VERBOSE: bool, G> PinnedDrop for Instrumented<F, VERBOSE, G> {
     fn drop(self: Pin<&mut Self>) {
         let this = self.project();
 
@@ -141,21 +345,30 @@ impl<F: Future, const VERBOSE: bool> PinnedDrop for Instrumented<F, VERBOSE> {
             State::Polled {
                 this_node,
                 this_context_id,
+                name,
             } => match current_context() {
                 // Context correct
                 Some(c) if c.id() == *this_context_id => {
-                    c.tree().remove_and_detach(*this_node);
+                    c.remove_and_detach(*this_node);
                 }
                 // Context changed
-                Some(_) => {
-                    tracing::warn!("future is dropped in a different context as it was first polled, cannot clean up!");
+                Some(c) => {
+                    c.report_context_mismatch(
+                        name,
+                        *this_context_id,
+                        "future is dropped in a different context as it was first polled, cannot clean up!",
+                    );
                 }
                 // Out of context
                 None => {
-                    tracing::warn!("future is not in a context, while it was when first polled, cannot clean up!");
+                    tracing::warn!(
+                        span = %name,
+                        first_task_id = this_context_id.0,
+                        "future is not in a context, while it was when first polled, cannot clean up!"
+                    );
                 }
             },
-            State::Initial(_) | State::Ready | State::Disabled => {}
+            State::Initial(_) | State::Lazy(_) | State::Ready | State::Disabled => {}
         }
     }
 }
@@ -0,0 +1,88 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use indextree::NodeId;
+
+use crate::context::TreeContext;
+use crate::registry::any_registry_created;
+use crate::root::current_context;
+use crate::Span;
+
+/// Enter a span as a child of the current span of the current task, which is closed when the
+/// returned guard is dropped.
+///
+/// This is the imperative counterpart of [`InstrumentAwait`](crate::InstrumentAwait), for
+/// instrumenting synchronous work inside an instrumented context, like a blocking section in a
+/// manual `poll` implementation or a [`poll_fn`](std::future::poll_fn). The guards should be
+/// dropped in the reverse order of entering, and never be held across an `.await`. The guard is
+/// `!Send`, so holding it across an `.await` in a future passed to `tokio::spawn` is rejected at
+/// compile time.
+///
+/// This returns a no-op guard if we're not instrumented.
+///
+/// ```
+/// # use await_tree::{enter_span, InstrumentAwait};
+/// # async fn example() {
+/// std::future::poll_fn(|_cx| {
+///     let _guard = enter_span("decode");
+///     // Decode the buffered messages...
+///     std::task::Poll::Ready(())
+/// })
+/// .instrument_await("recv")
+/// .await;
+/// # }
+/// ```
+pub fn enter_span(span: impl Into<Span>) -> SpanGuard {
+    let inner = if cfg!(feature = "disabled") || !any_registry_created() {
+        None
+    } else {
+        current_context()
+            .filter(|c| !c.exceeds_max_depth())
+            .map(|c| {
+                let node = c.push(span.into(), None);
+                (c, node)
+            })
+    };
+
+    SpanGuard {
+        inner,
+        _not_send: PhantomData,
+    }
+}
+
+/// The guard of a span entered with [`enter_span`], which closes the span on drop.
+#[must_use = "the span is closed immediately if the guard is dropped"]
+pub struct SpanGuard {
+    inner: Option<(Arc<TreeContext>, NodeId)>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        if let Some((context, node)) = self.inner.take() {
+            context.exit(node);
+        }
+    }
+}
+
+impl std::fmt::Debug for SpanGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpanGuard")
+            .field("entered", &self.inner.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export the await-trees of a registry as a self-contained HTML page.
+
+use std::fmt::Write;
+
+use crate::Registry;
+
+const STYLE: &str = "\
+body { font-family: monospace; }
+details { margin-left: 1.5em; }
+#trees > details { margin-left: 0; border-bottom: 1px solid #ddd; padding: 0.3em 0; }
+summary { cursor: pointer; }
+.leaf { margin-left: 2.7em; }
+.elapsed { color: #888; }
+.long { color: #c00; font-weight: bold; }
+.current { background: #ffeb99; }
+.detached { color: #888; font-style: italic; }
+";
+
+/// Toggle the order of the await-trees by the elapsed time of the root span.
+const SCRIPT: &str = "\
+function toggleSort(button) {
+  const asc = button.dataset.order === 'desc';
+  button.dataset.order = asc ? 'asc' : 'desc';
+  button.textContent = 'Sort by elapsed: ' + (asc ? 'ascending' : 'descending');
+  const trees = document.getElementById('trees');
+  Array.from(trees.children)
+    .sort((a, b) => (asc ? 1 : -1) * (a.dataset.elapsed - b.dataset.elapsed))
+    .forEach((tree) => trees.appendChild(tree));
+}
+";
+
+/// Escape the special characters in `s` for embedding in HTML.
+pub(crate) fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Registry {
+    /// Export a snapshot of all await-trees in this registry as a self-contained HTML page.
+    ///
+    /// Each await-tree is rendered as collapsible `<details>` elements under its key, sorted by
+    /// the elapsed time of the root span in descending order. The order can be toggled with the
+    /// button on the top of the page, which is done with a few lines of inline script. Spans
+    /// running for longer than
+    /// [`ConfigBuilder::warn_threshold`](crate::ConfigBuilder::warn_threshold) are highlighted, in
+    /// the same way as the `!!!` marker of the text format.
+    pub fn to_html(&self) -> String {
+        let mut trees = self.collect_all();
+        trees.sort_by_key(|(_, tree)| std::cmp::Reverse(tree.root_elapsed()));
+
+        let mut out = String::new();
+        write!(
+            out,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>await-tree</title>\n<style>\n{STYLE}</style>\n<script>\n{SCRIPT}</script>\n\
+             </head>\n<body>\n<button data-order=\"desc\" onclick=\"toggleSort(this)\">\
+             Sort by elapsed: descending</button>\n<div id=\"trees\">\n"
+        )
+        .unwrap();
+        for (key, tree) in trees {
+            write!(
+                out,
+                "<details open data-elapsed=\"{}\"><summary><b>{}</b> [{:.3?}]</summary>",
+                tree.root_elapsed().as_nanos(),
+                escape(&key.to_string()),
+                tree.root_elapsed()
+            )
+            .unwrap();
+            tree.write_html(&mut out);
+            out.push_str("</details>\n");
+        }
+        out.push_str("</div>\n</body>\n</html>\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r#"<a href="x">&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[cfg(not(feature = "disabled"))]
+    #[tokio::test]
+    async fn test_to_html() {
+        use crate::{Config, InstrumentAwait};
+
+        let registry = Registry::new(Config::default());
+        let _other = registry.register("other", "other root");
+
+        registry
+            .register("<task>", "root")
+            .instrument(async {
+                async {
+                    let html = registry.to_html();
+                    assert!(html.starts_with("<!DOCTYPE html>"), "{html}");
+                    assert!(html.contains("&lt;task&gt;"), "{html}");
+                    assert!(
+                        html.contains(r#"<span class="span current">leaf</span>"#),
+                        "{html}"
+                    );
+                    assert!(html.contains("other root"), "{html}");
+                    // The trees are sortable by the elapsed time of the root span.
+                    assert!(html.contains("onclick=\"toggleSort(this)\""), "{html}");
+                    assert_eq!(html.matches("<details open data-elapsed=").count(), 2);
+                }
+                .instrument_await("leaf")
+                .await
+            })
+            .await;
+    }
+}
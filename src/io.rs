@@ -0,0 +1,285 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use flexstr::SharedStr;
+use indextree::NodeId;
+use pin_project::{pin_project, pinned_drop};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::context::{ContextId, StepIn};
+use crate::registry::any_registry_created;
+use crate::root::current_context;
+use crate::Span;
+
+/// The state of the span of an instrumented object that can be polled for multiple times, like an
+/// IO object or a stream.
+pub(crate) enum State {
+    Initial(Span),
+    Polled {
+        this_node: NodeId,
+        this_context_id: ContextId,
+        /// The name of the span, kept for reporting the context mismatch.
+        name: SharedStr,
+    },
+    /// This span is disabled since the object is not polled in a context, the tree exceeds the
+    /// maximum depth, the span is evicted as a detached span, or the `disabled` feature is on.
+    Disabled,
+}
+
+impl State {
+    pub(crate) fn new(span: impl Into<Span>) -> Self {
+        // Drop the span without converting it if the instrumentation is stripped.
+        if cfg!(feature = "disabled") {
+            State::Disabled
+        } else {
+            State::Initial(span.into())
+        }
+    }
+
+    /// Step in the span, call `f`, then step out the span. `what` is the kind of the object for
+    /// reporting, like "io".
+    ///
+    /// Unlike [`Instrumented`](crate::Instrumented), the span is never popped when the poll is
+    /// ready, as an IO object can be polled for multiple times. It's only removed on drop.
+    pub(crate) fn enter<R>(&mut self, what: &str, f: impl FnOnce() -> R) -> R {
+        if cfg!(feature = "disabled") || !any_registry_created() {
+            // Fast path: the instrumentation is stripped, or there's no context to record the span
+            // in.
+            return f();
+        }
+        let context = current_context();
+
+        let context = match self {
+            State::Initial(span) => match context {
+                Some(c) if !c.exceeds_max_depth() => {
+                    // First polled, push a new span to the context.
+                    let span = std::mem::take(span);
+                    let name = span.name.clone();
+                    let node = c.push(span, None);
+                    *self = State::Polled {
+                        this_node: node,
+                        this_context_id: c.id(),
+                        name,
+                    };
+                    c
+                }
+                // Not in a context, or exceeds the maximum depth
+                _ => {
+                    *self = State::Disabled;
+                    return f();
+                }
+            },
+            State::Polled {
+                this_node,
+                this_context_id,
+                name,
+            } => match context {
+                // Context correct
+                Some(c) if c.id() == *this_context_id => {
+                    // Polled before, just step in.
+                    let step_in = c.tree().step_in(*this_node);
+                    match step_in {
+                        StepIn::Entered => {}
+                        StepIn::Evicted => {
+                            *self = State::Disabled;
+                            return f();
+                        }
+                        StepIn::Cyclic => return f(),
+                    }
+                    c
+                }
+                // Context changed
+                Some(c) => {
+                    c.report_context_mismatch(
+                        name,
+                        *this_context_id,
+                        &format!("{what} polled in a different context as it was first polled"),
+                    );
+                    return f();
+                }
+                // Out of context
+                None => {
+                    tracing::warn!(
+                        span = %name,
+                        first_task_id = this_context_id.0,
+                        "{what} polled not in a context, while it was when first polled"
+                    );
+                    return f();
+                }
+            },
+            State::Disabled => return f(),
+        };
+
+        let output = f();
+        context.tree().step_out();
+        output
+    }
+
+    /// Mark the span as failed, if it's recorded in the current context.
+    pub(crate) fn mark_failed(&self) {
+        if let State::Polled {
+            this_node,
+            this_context_id,
+            ..
+        } = self
+        {
+            if let Some(c) = current_context().filter(|c| c.id() == *this_context_id) {
+                c.tree().mark_failed(*this_node);
+            }
+        }
+    }
+
+    /// Remove the span from the context, used when the object is dropped. `what` is the kind of
+    /// the object for reporting, like "io".
+    pub(crate) fn remove(&mut self, what: &str) {
+        match self {
+            State::Polled {
+                this_node,
+                this_context_id,
+                name,
+            } => {
+                match current_context() {
+                    // Context correct
+                    Some(c) if c.id() == *this_context_id => {
+                        c.remove_and_detach(*this_node);
+                    }
+                    // Context changed
+                    Some(c) => {
+                        c.report_context_mismatch(
+                            name,
+                            *this_context_id,
+                            &format!("{what} is dropped in a different context as it was first polled, cannot clean up!"),
+                        );
+                    }
+                    // Out of context
+                    None => {
+                        tracing::warn!(
+                            span = %name,
+                            first_task_id = this_context_id.0,
+                            "{what} is not in a context, while it was when first polled, cannot clean up!"
+                        );
+                    }
+                }
+            }
+            State::Initial(_) | State::Disabled => {}
+        }
+    }
+}
+
+/// The IO object for [`InstrumentIo`].
+#[pin_project(PinnedDrop)]
+pub struct InstrumentedIo<T> {
+    #[pin]
+    inner: T,
+    state: State,
+}
+
+impl<T> InstrumentedIo<T> {
+    fn new(inner: T, span: impl Into<Span>) -> Self {
+        Self {
+            inner,
+            state: State::new(span),
+        }
+    }
+
+    /// Returns a reference to the inner IO object.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner IO object.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for InstrumentedIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        this.state.enter("io", || this.inner.poll_read(cx, buf))
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for InstrumentedIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        this.state.enter("io", || this.inner.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        this.state.enter("io", || this.inner.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        this.state.enter("io", || this.inner.poll_shutdown(cx))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        this.state
+            .enter("io", || this.inner.poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[pinned_drop]
+impl<T> PinnedDrop for InstrumentedIo<T> {
+    fn drop(self: Pin<&mut Self>) {
+        self.project().state.remove("io");
+    }
+}
+
+/// Attach spans to an [`AsyncRead`] or [`AsyncWrite`] object to be traced in the await-tree.
+///
+/// The span is stepped into on every poll of the IO object and persists across polls, until the
+/// object is dropped. This is the IO analogue of [`InstrumentAwait`](crate::InstrumentAwait).
+pub trait InstrumentIo: Sized {
+    /// Instrument the reader with a span.
+    fn instrument_read(self, span: impl Into<Span>) -> InstrumentedIo<Self>
+    where
+        Self: AsyncRead,
+    {
+        InstrumentedIo::new(self, span)
+    }
+
+    /// Instrument the writer with a span.
+    fn instrument_write(self, span: impl Into<Span>) -> InstrumentedIo<Self>
+    where
+        Self: AsyncWrite,
+    {
+        InstrumentedIo::new(self, span)
+    }
+}
+impl<T> InstrumentIo for T {}
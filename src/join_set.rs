@@ -0,0 +1,121 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::task::{AbortHandle, JoinError, JoinSet};
+
+use crate::{Registry, Span, Tree};
+
+/// Key type for tasks spawned in a [`TrackedJoinSet`].
+///
+/// Intentionally made private so that the trees of a set can only be retrieved through
+/// [`TrackedJoinSet::trees`] or the `collect_all` family of the [`Registry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TrackedKey {
+    /// The id of the join set.
+    set: u64,
+    /// The auto-incremented sequence number of the task in the join set.
+    seq: u64,
+}
+
+/// A wrapper of [`tokio::task::JoinSet`] that registers each spawned task in the given
+/// [`Registry`], so that the await-trees of the whole group of tasks can be dumped together.
+///
+/// Tasks are registered with an auto-incremented key private to this set. The entry of a task is
+/// removed from the registry once the task finishes, so [`TrackedJoinSet::trees`] only reflects
+/// the tasks that are still running.
+pub struct TrackedJoinSet<T> {
+    id: u64,
+    next_seq: u64,
+    registry: Registry,
+    inner: JoinSet<T>,
+}
+
+impl<T> std::fmt::Debug for TrackedJoinSet<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrackedJoinSet")
+            .field("id", &self.id)
+            .field("len", &self.inner.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: 'static> TrackedJoinSet<T> {
+    /// Create a new empty set, whose tasks will be registered in the given `registry`.
+    pub fn new(registry: Registry) -> Self {
+        static ID: AtomicU64 = AtomicU64::new(0);
+        let id = ID.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            id,
+            next_seq: 0,
+            registry,
+            inner: JoinSet::new(),
+        }
+    }
+
+    /// Spawns the given future instrumented with the given root [`Span`] on this set, returning
+    /// an [`AbortHandle`] for it.
+    pub fn spawn<F>(&mut self, root_span: impl Into<Span>, future: F) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send,
+    {
+        let key = TrackedKey {
+            set: self.id,
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+
+        let root = self.registry.register(key, root_span);
+        self.inner.spawn(root.instrument(future))
+    }
+
+    /// Waits until one of the tasks in the set completes and returns its output.
+    ///
+    /// Returns `None` if the set is empty. See [`JoinSet::join_next`] for more information.
+    pub async fn join_next(&mut self) -> Option<Result<T, JoinError>> {
+        self.inner.join_next().await
+    }
+
+    /// Aborts all tasks in this set. The tasks will be removed from the set once they're joined.
+    pub fn abort_all(&mut self) {
+        self.inner.abort_all();
+    }
+
+    /// Returns the number of tasks currently in the set.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Collect the snapshots of the await-trees of all running tasks in this set, in the order of
+    /// spawning.
+    pub fn trees(&self) -> Vec<Tree> {
+        let mut trees = self
+            .registry
+            .collect::<TrackedKey>()
+            .into_iter()
+            .filter(|(k, _)| k.set == self.id)
+            .collect::<Vec<_>>();
+        trees.sort_by_key(|(k, _)| k.seq);
+        trees.into_iter().map(|(_, tree)| tree).collect()
+    }
+}
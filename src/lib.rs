@@ -70,55 +70,385 @@
 #![forbid(missing_docs)]
 
 use std::future::Future;
+#[cfg(feature = "location")]
+use std::panic::Location;
+use std::pin::Pin;
 
+mod block_on;
+mod chrome_trace;
 mod context;
 mod future;
 mod global;
+mod guard;
+mod html;
+mod io;
+mod join_set;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod obj_utils;
 mod registry;
+mod remount;
 mod root;
 mod spawn;
+mod stream;
+mod subscribe;
+mod time;
+mod watchdog;
 
-pub use context::{current_tree, Tree};
+pub use block_on::block_on;
+pub use context::{
+    current_span_handle, current_task_id, current_tree, rename_current_span, set_current_progress,
+    DetachReason, SpanHandle, Tree, TreeDiff, TreeNode,
+};
 pub use future::Instrumented;
 pub use global::init_global_registry;
-pub use registry::{AnyKey, Config, ConfigBuilder, ConfigBuilderError, Key, Registry};
+pub use guard::{enter_span, SpanGuard};
+pub use io::{InstrumentIo, InstrumentedIo};
+pub use join_set::TrackedJoinSet;
+pub use registry::{
+    AnyKey, Config, ConfigBuilder, ConfigBuilderError, ContextMismatchCallback, DuplicateKeyError,
+    Key, RegisterCallback, Registry, SlowestBy, SpanCloseCallback, ToRootSpan, TreeStyle,
+};
+pub use remount::{with_parent_span, WithParentSpan};
 pub use root::TreeRoot;
-pub use spawn::{spawn, spawn_anonymous};
+pub use spawn::{spawn, spawn_anonymous, spawn_derived_root, spawn_local, spawn_on};
+pub use stream::{
+    InstrumentStream, InstrumentTryStream, InstrumentedStream, InstrumentedTryStream,
+};
+pub use subscribe::{TreeEvent, TreeEventStream, TreeWatchStream};
 
 /// A cheaply cloneable span in the await-tree.
+///
+/// Span names of at most [`Span::INLINE_CAPACITY`] bytes are stored inline without heap
+/// allocation, no matter whether they're converted from a `&str` or a `String`. Longer names are
+/// stored in a shared heap allocation.
 #[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Span(flexstr::SharedStr);
+pub struct Span {
+    name: flexstr::SharedStr,
+    /// The coarse category of the span, like "io" or "compute".
+    category: Option<&'static str>,
+    /// The source location where the future is instrumented, set if
+    /// [`ConfigBuilder::show_location`] is enabled.
+    #[cfg(feature = "location")]
+    location: Option<&'static Location<'static>>,
+    /// The name of the output type of the instrumented future, set if
+    /// [`ConfigBuilder::show_output_type`] is enabled.
+    output_type: Option<&'static str>,
+    /// The order among its siblings, set with [`SpanExt::order`].
+    order: Option<i32>,
+    /// The threshold of the elapsed time for the span to be considered long-running, overriding
+    /// [`ConfigBuilder::warn_threshold`], set with [`SpanExt::warn_after`].
+    warn_after: Option<std::time::Duration>,
+    /// The stable id for correlating the span across snapshots, set with [`SpanExt::with_id`].
+    user_id: Option<u64>,
+}
 
 impl Span {
-    pub(crate) fn as_str(&self) -> &str {
-        self.0.as_str()
+    /// The maximum length in bytes of a span name that is stored inline without heap allocation,
+    /// which is 22 on 64-bit platforms.
+    pub const INLINE_CAPACITY: usize = flexstr::STRING_SIZED_INLINE;
+
+    /// Create a span referring to the given static name, which never copies or allocates.
+    ///
+    /// Unlike converting from a `&'static str` with [`Into`], which copies the name, this is a
+    /// `const fn` that can be used to define a span once and hand out cheap copies:
+    ///
+    /// ```
+    /// # use await_tree::Span;
+    /// static POLL_SOURCE: Span = Span::from_static("poll source");
+    ///
+    /// let span = POLL_SOURCE.clone();
+    /// ```
+    pub const fn from_static(name: &'static str) -> Self {
+        Self {
+            name: flexstr::SharedStr::from_static(name),
+            category: None,
+            #[cfg(feature = "location")]
+            location: None,
+            output_type: None,
+            order: None,
+            warn_after: None,
+            user_id: None,
+        }
+    }
+
+    /// Create a span with the given name stored inline, which never allocates on the heap.
+    ///
+    /// This is useful for spans in hot loops. Check the length of the name at compile time if
+    /// it's known:
+    ///
+    /// ```
+    /// # use await_tree::Span;
+    /// const NAME: &str = "hot operator";
+    /// const _: () = assert!(NAME.len() <= Span::INLINE_CAPACITY);
+    ///
+    /// let span = Span::from_inline(NAME);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is longer than [`Span::INLINE_CAPACITY`] bytes.
+    pub fn from_inline(name: &str) -> Self {
+        let name = flexstr::SharedStr::try_inline(name).unwrap_or_else(|name| {
+            panic!(
+                "span name `{name}` exceeds the inline capacity of {} bytes",
+                Self::INLINE_CAPACITY
+            )
+        });
+        Self {
+            name,
+            category: None,
+            #[cfg(feature = "location")]
+            location: None,
+            output_type: None,
+            order: None,
+            warn_after: None,
+            user_id: None,
+        }
+    }
+
+    /// Returns the name of the span.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Returns the stable id of the span set with [`SpanExt::with_id`], if any.
+    pub fn user_id(&self) -> Option<u64> {
+        self.user_id
+    }
+
+    /// Append the name of type `T` to the span, like `name -> T`.
+    pub(crate) fn with_output_type<T>(self) -> Self {
+        Self {
+            output_type: Some(std::any::type_name::<T>()),
+            ..self
+        }
+    }
+
+    /// Replace the name of the span, keeping the other attributes.
+    pub(crate) fn rename(&mut self, name: impl AsRef<str>) {
+        self.name = flexstr::SharedStr::from_ref(name);
     }
 }
 
 impl<S: AsRef<str>> From<S> for Span {
     fn from(value: S) -> Self {
-        Self(flexstr::SharedStr::from_ref(value))
+        Self {
+            name: flexstr::SharedStr::from_ref(value),
+            category: None,
+            #[cfg(feature = "location")]
+            location: None,
+            output_type: None,
+            order: None,
+            warn_after: None,
+            user_id: None,
+        }
     }
 }
 
 impl std::fmt::Display for Span {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+        self.name.fmt(f)?;
+        if let Some(output_type) = self.output_type {
+            write!(f, " -> {output_type}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Extension methods for building a [`Span`].
+pub trait SpanExt: Into<Span> {
+    /// Tag the span with a coarse category, like "io" or "compute".
+    ///
+    /// The total elapsed time of each category can be retrieved with
+    /// [`Registry::total_elapsed_by_category`].
+    fn category(self, category: &'static str) -> Span {
+        let mut span = self.into();
+        span.category = Some(category);
+        span
+    }
+
+    /// Set the order of the span among its siblings in the await-tree.
+    ///
+    /// Siblings are sorted by the order first, then by the start time, where spans without an
+    /// order come first. This gives a stable order of joined futures started at nearly the same
+    /// time, for example, to always show the "read" arm before the "write" one, so that
+    /// consecutive dumps are diffable.
+    fn order(self, order: i32) -> Span {
+        let mut span = self.into();
+        span.order = Some(order);
+        span
+    }
+
+    /// Attach a stable id to the span, for correlating the same logical span across snapshots.
+    ///
+    /// Unlike the `id` of span nodes in the serialized tree, which is an internal index only
+    /// meaningful within a snapshot, this id is chosen by the user, like a request id, and kept as
+    /// is in the `user_id` field of the serialized tree. This allows external systems to build a
+    /// timeline of a span from multiple dumps.
+    fn with_id(self, id: u64) -> Span {
+        let mut span = self.into();
+        span.user_id = Some(id);
+        span
+    }
+
+    /// Set the threshold of the elapsed time for the span to be considered long-running,
+    /// overriding [`ConfigBuilder::warn_threshold`] for this span only.
+    ///
+    /// This allows a tight threshold on the spans known to be fast, without flagging the ones slow
+    /// by design. The threshold affects the `!!!` marker in the output, the highlighting in the
+    /// HTML output, and the stuck spans reported by the watchdog.
+    fn warn_after(self, threshold: std::time::Duration) -> Span {
+        let mut span = self.into();
+        span.warn_after = Some(threshold);
+        span
+    }
+}
+impl<T: Into<Span>> SpanExt for T {}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Span {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Span {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from)
     }
 }
 
 /// Attach spans to a future to be traced in the await-tree.
+///
+/// For a future whose branch is chosen at runtime, like `futures::future::Either`,
+/// instrument each branch with its own span before combining them, which shows a distinct span
+/// per branch without boxing. The combined future can still be instrumented with a common span:
+///
+/// ```
+/// # use await_tree::InstrumentAwait;
+/// use futures::future::Either;
+///
+/// # async fn example(cached: bool) {
+/// let read = if cached {
+///     Either::Left(async { 1 }.instrument_await("read cache"))
+/// } else {
+///     Either::Right(async { 2 }.instrument_await("read disk"))
+/// };
+/// read.instrument_await("read").await;
+/// # }
+/// ```
+///
+/// As the span is pushed when the future is first polled, only the span of the chosen branch
+/// shows up, as a child of the common span. If the combined future is replaced with another
+/// branch after being polled, the span of the old branch is removed on drop like any other
+/// instrumented future.
+///
+/// The span of a future is popped once the future is ready. To instrument a stream, whose span
+/// should persist across the yielded items, use [`InstrumentStream`] instead.
 pub trait InstrumentAwait: Future + Sized {
     /// Instrument the future with a span.
+    #[track_caller]
     fn instrument_await(self, span: impl Into<Span>) -> Instrumented<Self, false> {
-        Instrumented::new(self, span.into())
+        Instrumented::new(self, span)
+    }
+
+    /// Instrument the future with a span only if `cond` is true, otherwise the future behaves
+    /// exactly like the bare one.
+    ///
+    /// Both cases share the same type, which is useful for sampling instrumentation in hot paths
+    /// without boxing the future.
+    #[track_caller]
+    fn instrument_await_if(self, cond: bool, span: impl Into<Span>) -> Instrumented<Self, false> {
+        let instrumented = Instrumented::new(self, span);
+        if cond {
+            instrumented
+        } else {
+            instrumented.disabled()
+        }
+    }
+
+    /// Instrument the future with a span started at the given time, instead of the time when the
+    /// future is first polled.
+    ///
+    /// This is useful for work resumed from a persisted state, so that the await-tree shows the
+    /// true age of the work.
+    #[track_caller]
+    fn instrument_await_since(
+        self,
+        span: impl Into<Span>,
+        start_time: std::time::Instant,
+    ) -> Instrumented<Self, false> {
+        Instrumented::new(self, span).with_start_time(time::Instant::from_std(start_time))
+    }
+
+    /// Instrument the future with a span, which is recorded as soon as the future is constructed if
+    /// [`ConfigBuilder::record_unpolled`] is set, instead of when it's first polled.
+    ///
+    /// The span is shown as `(not yet polled)` until the future is first polled, and is removed if
+    /// the future is dropped without being polled. This is useful for finding out the branches
+    /// that never ran in a busy `select` or `join`. Without the configuration, this is equivalent
+    /// to [`InstrumentAwait::instrument_await`].
+    #[track_caller]
+    fn instrument_await_eager(self, span: impl Into<Span>) -> Instrumented<Self, false> {
+        Instrumented::new(self, span).eager()
     }
 
     /// Instrument the future with a verbose span, which is optionally enabled based on the registry
     /// configuration.
+    #[track_caller]
     fn verbose_instrument_await(self, span: impl Into<Span>) -> Instrumented<Self, true> {
-        Instrumented::new(self, span.into())
+        Instrumented::new(self, span)
+    }
+
+    /// Instrument the future with a span lazily built by the given closure.
+    ///
+    /// The closure is only called when the future is first polled in an await-tree context, so
+    /// the cost of building the span, like formatting, is not paid if the span is never recorded.
+    #[track_caller]
+    fn instrument_await_with<G>(self, span_fn: G) -> Instrumented<Self, false, G>
+    where
+        G: FnOnce() -> Span,
+    {
+        Instrumented::new_lazy(self, span_fn)
+    }
+
+    /// Instrument the future with a verbose span lazily built by the given closure.
+    ///
+    /// The closure is not called if the verbose span is disabled based on the registry
+    /// configuration. See [`InstrumentAwait::instrument_await_with`] for more information.
+    #[track_caller]
+    fn verbose_instrument_await_with<G>(self, span_fn: G) -> Instrumented<Self, true, G>
+    where
+        G: FnOnce() -> Span,
+    {
+        Instrumented::new_lazy(self, span_fn)
+    }
+
+    /// Box and pin the future, then instrument it with a span, keeping the type of the future.
+    ///
+    /// The future is moved to the heap, so the instrumented future is only as large as a pointer
+    /// plus the span, no matter how large the inner future is. This avoids stack overflows from
+    /// deeply nested futures, and allows recursive `async fn`s without erasing the type.
+    #[track_caller]
+    fn instrument_await_boxed(self, span: impl Into<Span>) -> Instrumented<Pin<Box<Self>>, false> {
+        Instrumented::new(Box::pin(self), span)
+    }
+
+    /// Box and pin the future, then instrument it with a span.
+    ///
+    /// The type of the future is erased, which is useful for storing instrumented futures of
+    /// different types in a collection.
+    #[track_caller]
+    fn instrument_boxed<'a>(
+        self,
+        span: impl Into<Span>,
+    ) -> Instrumented<Pin<Box<dyn Future<Output = Self::Output> + Send + 'a>>, false>
+    where
+        Self: Send + 'a,
+    {
+        Instrumented::new(Box::pin(self), span)
     }
 }
 impl<F> InstrumentAwait for F where F: Future {}
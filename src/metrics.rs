@@ -0,0 +1,127 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export the metrics of a registry in the [Prometheus text format].
+//!
+//! [Prometheus text format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::fmt::Write;
+
+use crate::Registry;
+
+/// The upper bounds in seconds of the buckets of the histogram of root span ages.
+const ROOT_AGE_BUCKETS: [f64; 8] = [0.1, 1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 86400.0];
+
+impl Registry {
+    /// Export the metrics of the live await-trees in this registry in the [Prometheus text
+    /// format], to be served on a metrics endpoint:
+    ///
+    /// - `await_tree_active_trees`: the gauge of the number of await-trees.
+    /// - `await_tree_active_spans`: the gauge of the total number of active spans, see
+    ///   [`Registry::total_active_spans`].
+    /// - `await_tree_root_age_seconds`: the histogram of the elapsed time of root spans, i.e., how
+    ///   long the tasks have been running.
+    ///
+    /// No tree is cloned, while each tree is briefly locked. Imported trees are not counted.
+    ///
+    /// [Prometheus text format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn prometheus_metrics(&self) -> String {
+        let mut trees = 0;
+        let mut spans = 0;
+        let mut buckets = [0; ROOT_AGE_BUCKETS.len()];
+        let mut age_sum = 0.0;
+        self.for_each_tree(|_, tree| {
+            trees += 1;
+            spans += tree.active_node_count();
+            let age = tree.root_elapsed().as_secs_f64();
+            age_sum += age;
+            for (bucket, &le) in buckets.iter_mut().zip(&ROOT_AGE_BUCKETS) {
+                if age <= le {
+                    *bucket += 1;
+                }
+            }
+        });
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "# HELP await_tree_active_trees The number of active await-trees."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE await_tree_active_trees gauge").unwrap();
+        writeln!(out, "await_tree_active_trees {trees}").unwrap();
+        writeln!(
+            out,
+            "# HELP await_tree_active_spans The total number of active spans in all await-trees."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE await_tree_active_spans gauge").unwrap();
+        writeln!(out, "await_tree_active_spans {spans}").unwrap();
+        writeln!(
+            out,
+            "# HELP await_tree_root_age_seconds The elapsed time of the root spans of await-trees."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE await_tree_root_age_seconds histogram").unwrap();
+        for (count, le) in buckets.iter().zip(ROOT_AGE_BUCKETS) {
+            writeln!(
+                out,
+                "await_tree_root_age_seconds_bucket{{le=\"{le}\"}} {count}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "await_tree_root_age_seconds_bucket{{le=\"+Inf\"}} {trees}"
+        )
+        .unwrap();
+        writeln!(out, "await_tree_root_age_seconds_sum {age_sum}").unwrap();
+        writeln!(out, "await_tree_root_age_seconds_count {trees}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, Registry};
+
+    #[test]
+    fn test_prometheus_metrics() {
+        let registry = Registry::new(Config::default());
+        let _1_i32 = registry.register(1_i32, "1");
+        let _2_i32 = registry.register(2_i32, "2");
+
+        let metrics = registry.prometheus_metrics();
+        let lines = metrics.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"await_tree_active_trees 2"), "{metrics}");
+        assert!(lines.contains(&"await_tree_active_spans 2"), "{metrics}");
+        assert!(
+            lines.contains(&"await_tree_root_age_seconds_bucket{le=\"0.1\"} 2"),
+            "{metrics}"
+        );
+        assert!(
+            lines.contains(&"await_tree_root_age_seconds_bucket{le=\"+Inf\"} 2"),
+            "{metrics}"
+        );
+        assert!(
+            lines.contains(&"await_tree_root_age_seconds_count 2"),
+            "{metrics}"
+        );
+        // Every sample line is a metric name followed by a number.
+        for line in lines.iter().filter(|l| !l.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{line}");
+        }
+    }
+}
@@ -12,32 +12,283 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::any::Any;
+use std::any::{Any, TypeId};
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{Debug, Display};
 use std::hash::Hash;
-use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
 
 use derive_builder::Builder;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use weak_table::WeakValueHashMap;
 
 use crate::context::{ContextId, Tree, TreeContext};
 use crate::obj_utils::{DynEq, DynHash};
+use crate::subscribe::Subscribers;
 use crate::{Span, TreeRoot};
 
+#[cfg(feature = "serde")]
+mod serde_impl;
+
+/// Whether any registry has ever been created in this process, including the global one.
+static ANY_REGISTRY_CREATED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether any registry has ever been created.
+///
+/// If not, no await-tree context can exist, so the instrumented futures can skip looking up the
+/// task-local context, making the instrumentation nearly free for binaries that never enable it.
+pub(crate) fn any_registry_created() -> bool {
+    ANY_REGISTRY_CREATED.load(Ordering::Relaxed)
+}
+
+/// The callback invoked with the span and its elapsed time when a span is closed.
+pub type SpanCloseCallback = Arc<dyn Fn(&Span, Duration) + Send + Sync>;
+
+/// The callback invoked with the key when a new await-tree is registered.
+pub type RegisterCallback = Arc<dyn Fn(&AnyKey) + Send + Sync>;
+
+/// The callback invoked with the span, the id of the task where the span was first polled, and
+/// the id of the current task, when an instrumented future is polled or dropped in a different
+/// task. See [`current_task_id`](crate::current_task_id) for the task ids.
+///
+/// Only the name of the span is kept for reporting, so other attributes like the category are not
+/// available in the callback.
+pub type ContextMismatchCallback = Arc<dyn Fn(&Span, u64, u64) + Send + Sync>;
+
+/// The style of the indentation of spans in the output of the await-tree, set with
+/// [`ConfigBuilder::tree_style`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TreeStyle {
+    /// Indent each level with two spaces, without connector lines.
+    #[default]
+    Spaces,
+    /// Draw the connector lines with Unicode box-drawing characters, like `├─`, `└─` and `│`.
+    Unicode,
+    /// Draw the connector lines with ASCII characters, like `|-`, `` `- `` and `|`.
+    Ascii,
+}
+
+impl TreeStyle {
+    /// Returns the prefix of the line of a span, and the prefix to be inherited by its children,
+    /// given whether the span is the last child of its parent.
+    pub(crate) fn branch(self, last: bool) -> (&'static str, &'static str) {
+        match (self, last) {
+            (Self::Spaces, _) => ("  ", "  "),
+            (Self::Unicode, false) => ("├─ ", "│  "),
+            (Self::Unicode, true) => ("└─ ", "   "),
+            (Self::Ascii, false) => ("|- ", "|  "),
+            (Self::Ascii, true) => ("`- ", "   "),
+        }
+    }
+}
+
+/// The metric to rank the await-trees by in [`Registry::slowest`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlowestBy {
+    /// The elapsed time of the root span, i.e., how long the task has been running.
+    #[default]
+    RootElapsed,
+    /// The maximum elapsed time of the leaf spans, including the detached ones, i.e., how long
+    /// the task has been stuck at a single point. Falls back to the elapsed time of the root span
+    /// if there's no other span.
+    MaxLeafElapsed,
+}
+
 /// Configuration for an await-tree registry, which affects the behavior of all await-trees in the
 /// registry.
-#[derive(Debug, Clone, Builder)]
+#[derive(Clone, Builder)]
 #[builder(default)]
 pub struct Config {
     /// Whether to include the **verbose** span in the await-tree.
-    verbose: bool,
+    pub(crate) verbose: bool,
+
+    /// The maximum depth of the await-tree, where the root span is at depth 1.
+    ///
+    /// Spans that would exceed this depth are not recorded, while their futures are still polled
+    /// as usual. This bounds the size of the tree for pathological cases like unbounded recursion.
+    #[builder(setter(strip_option))]
+    pub(crate) max_depth: Option<usize>,
+
+    /// The maximum number of detached subtrees to keep in an await-tree.
+    ///
+    /// A span is detached if its parent is closed while it's still pending, for example, the
+    /// losing arm of a `select` that is kept for later polling. If a future is repeatedly detached
+    /// but never remounted, the detached subtrees accumulate until the task ends. With this limit,
+    /// the oldest detached subtrees are evicted, and the evicted spans will not be recorded even
+    /// if they're polled again.
+    #[builder(setter(strip_option))]
+    pub(crate) max_detached: Option<usize>,
+
+    /// The callback invoked when an instrumented future becomes ready and its span is closed.
+    ///
+    /// This can be used to record the latency of spans, for example, into a histogram metric. The
+    /// callback is invoked outside the lock of the await-tree, so it's safe to access the registry
+    /// or the current tree in the callback.
+    #[builder(setter(strip_option))]
+    pub(crate) on_span_close: Option<SpanCloseCallback>,
+
+    /// The callback invoked when an instrumented future is polled or dropped in a different task
+    /// than the one it was first polled in, which is likely a bug of moving futures across tasks.
+    /// A warning is always logged in this case, with the same information as the arguments.
+    #[builder(setter(strip_option))]
+    pub(crate) on_context_mismatch: Option<ContextMismatchCallback>,
+
+    /// The callback invoked when a new await-tree is registered, including the anonymous ones.
+    ///
+    /// This can be used to log or count the started tasks centrally, or to integrate with an
+    /// external inventory of tasks. The callback is invoked after the tree is inserted and outside
+    /// the lock of the registry, so it's safe to access the registry in the callback.
+    #[builder(setter(strip_option))]
+    pub(crate) on_register: Option<RegisterCallback>,
+
+    /// The maximum number of await-trees of aborted tasks to keep for post-mortem, which can be
+    /// retrieved with [`Registry::recent_aborted`]. `0` disables the recording.
+    ///
+    /// A task is considered aborted if its instrumented root future is dropped before completion,
+    /// for example, when the task is cancelled with [`tokio::task::JoinHandle::abort`].
+    pub(crate) aborted_capacity: usize,
+
+    /// Whether to remove the entries of dropped await-trees from the registry on every
+    /// registration.
+    ///
+    /// The entries of dropped await-trees are kept as expired weak references until they're
+    /// cleaned up, which costs iterations on collecting. Enabling this makes the cost of
+    /// registration linear in the number of entries, so in churny workloads, prefer calling
+    /// [`Registry::gc`] periodically instead.
+    pub(crate) auto_gc: bool,
+
+    /// The threshold of the elapsed time for a span to be considered long-running. Defaults to 10
+    /// seconds.
+    ///
+    /// Long-running spans are highlighted with `!!!` in the output of the await-tree, and reported
+    /// by the watchdog spawned with [`Registry::spawn_watchdog`].
+    pub(crate) warn_threshold: Duration,
+
+    /// Whether to show the category of spans in the output of the await-tree, set with
+    /// [`SpanExt::category`](crate::SpanExt::category).
+    pub(crate) show_category: bool,
+
+    /// The style of the indentation of spans in the output of the await-tree. Defaults to
+    /// [`TreeStyle::Spaces`].
+    pub(crate) tree_style: TreeStyle,
+
+    /// The maximum number of characters of span names in the output of the await-tree. Longer
+    /// names are truncated with an ellipsis.
+    ///
+    /// This only affects the `Display` output, while the full names are kept in the tree and the
+    /// serialized output.
+    #[builder(setter(strip_option))]
+    pub(crate) max_span_name_len: Option<usize>,
+
+    /// Whether to collapse the consecutive sibling spans with identical spans and no children into
+    /// a single line like `fetch (x1000) [1.002s..1.010s]`, showing the number of spans and the
+    /// range of their elapsed time. Useful for the wide fan-outs like `join_all`.
+    ///
+    /// Like [`ConfigBuilder::max_span_name_len`], this only affects the `Display` output.
+    pub(crate) collapse_siblings: bool,
+
+    /// Whether to append the output type of the instrumented future to its span, like
+    /// `recv -> Option<Message>`. Useful for telling the arms of a `select` apart.
+    ///
+    /// This is purely diagnostic, so it's disabled by default.
+    pub(crate) show_output_type: bool,
+
+    /// Whether to show the source location where each future is instrumented in its span, like
+    /// `poll (src/foo.rs:42)`. Useful for telling apart spans with the same name.
+    ///
+    /// This requires the `location` feature, which captures the location at the call of
+    /// [`InstrumentAwait`](crate::InstrumentAwait) methods. Without the feature, the location is
+    /// neither captured nor stored, and this option has no effect.
+    pub(crate) show_location: bool,
+
+    /// The maximum number of buffered events for each subscriber returned by
+    /// [`Registry::subscribe`]. Defaults to 1024.
+    pub(crate) subscriber_capacity: usize,
+
+    /// Whether to panic if the await-tree has not collapsed to the root span when the instrumented
+    /// root future completes, which indicates that some spans are leaked, for example, an
+    /// instrumented future polled in this task is kept alive beyond its completion.
+    ///
+    /// This is intended for catching instrumentation bugs in tests, and should never be enabled
+    /// in production.
+    pub(crate) strict: bool,
+
+    /// The fraction of instrumented futures to record in the await-tree, between `0.0` and `1.0`.
+    /// Defaults to `1.0`, i.e., all futures are recorded.
+    ///
+    /// This bounds the overhead when a task fans out to a huge number of tiny futures. Whether a
+    /// future is sampled is decided consistently on every poll based on the context and the
+    /// address of the future, and the futures not sampled are polled as if they're not
+    /// instrumented. The root span is always recorded, and a future not sampled is still recorded
+    /// once it has been running for longer than [`ConfigBuilder::warn_threshold`], so that the
+    /// long-running spans are never missed.
+    pub(crate) sample_rate: f64,
+
+    /// Whether to record the spans of futures instrumented with
+    /// [`InstrumentAwait::instrument_await_eager`](crate::InstrumentAwait::instrument_await_eager)
+    /// as soon as they're constructed, shown as `(not yet polled)` until they're first polled.
+    ///
+    /// This reveals the branches of a busy `select` or `join` that are starved, at the cost of
+    /// more spans in the tree, so it's disabled by default.
+    pub(crate) record_unpolled: bool,
 }
 
 #[allow(clippy::derivable_impls)]
 impl Default for Config {
     fn default() -> Self {
-        Self { verbose: false }
+        Self {
+            verbose: false,
+            max_depth: None,
+            max_detached: None,
+            on_span_close: None,
+            on_context_mismatch: None,
+            on_register: None,
+            aborted_capacity: 0,
+            auto_gc: false,
+            warn_threshold: Duration::from_secs(10),
+            show_category: false,
+            tree_style: TreeStyle::default(),
+            max_span_name_len: None,
+            collapse_siblings: false,
+            show_output_type: false,
+            show_location: false,
+            subscriber_capacity: 1024,
+            strict: false,
+            sample_rate: 1.0,
+            record_unpolled: false,
+        }
+    }
+}
+
+impl Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("verbose", &self.verbose)
+            .field("max_depth", &self.max_depth)
+            .field("max_detached", &self.max_detached)
+            .field("on_span_close", &self.on_span_close.as_ref().map(|_| ".."))
+            .field(
+                "on_context_mismatch",
+                &self.on_context_mismatch.as_ref().map(|_| ".."),
+            )
+            .field("on_register", &self.on_register.as_ref().map(|_| ".."))
+            .field("aborted_capacity", &self.aborted_capacity)
+            .field("auto_gc", &self.auto_gc)
+            .field("warn_threshold", &self.warn_threshold)
+            .field("show_category", &self.show_category)
+            .field("tree_style", &self.tree_style)
+            .field("max_span_name_len", &self.max_span_name_len)
+            .field("collapse_siblings", &self.collapse_siblings)
+            .field("show_output_type", &self.show_output_type)
+            .field("show_location", &self.show_location)
+            .field("subscriber_capacity", &self.subscriber_capacity)
+            .field("strict", &self.strict)
+            .field("sample_rate", &self.sample_rate)
+            .field("record_unpolled", &self.record_unpolled)
+            .finish()
     }
 }
 
@@ -45,11 +296,48 @@ impl Default for Config {
 ///
 /// All thread-safe types that can be used as a key of a hash map are automatically implemented with
 /// this trait.
+///
+/// The key is moved into a single allocation of the type-erased [`AnyKey`] on registration, and
+/// is never cloned by the registry. So an owned key is not allocated twice unless it owns a heap
+/// allocation itself. Cheap key types are recommended:
+///
+/// - Integers, `&'static str`, or composite keys of them like `(&'static str, u32)`, which need no
+///   allocation other than the one of [`AnyKey`].
+/// - `Arc<str>` for dynamic names shared with the rest of the application, which can be cloned
+///   cheaply for looking up the await-tree with [`Registry::get`].
+///
+/// For composite keys, prefer a tuple or a struct of the fields over formatting them into a
+/// `String`, which allocates for formatting and cannot be looked up by the fields.
 pub trait Key: Hash + Eq + Debug + Send + Sync + 'static {}
 impl<T> Key for T where T: Hash + Eq + Debug + Send + Sync + 'static {}
 
+/// A key that can derive the root [`Span`] of its await-tree, used by
+/// [`spawn_derived_root`](crate::spawn_derived_root) and [`Registry::spawn_derived_root`].
+///
+/// All types that implement [`Display`] are automatically implemented with this trait, where the
+/// root span is the formatted key. Implement [`Display`] for the key type to customize the root
+/// span.
+pub trait ToRootSpan {
+    /// Returns the root span of the await-tree registered with this key.
+    fn to_root_span(&self) -> Span;
+}
+
+impl<T> ToRootSpan for T
+where
+    T: Display + ?Sized,
+{
+    fn to_root_span(&self) -> Span {
+        self.to_string().into()
+    }
+}
+
 /// The object-safe version of [`Key`], automatically implemented.
-trait ObjKey: DynHash + DynEq + Debug + Send + Sync + 'static {}
+trait ObjKey: DynHash + DynEq + Debug + Send + Sync + 'static {
+    /// Returns the name of the concrete key type.
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
 impl<T> ObjKey for T where T: DynHash + DynEq + Debug + Send + Sync + 'static {}
 
 /// Key type for anonymous await-trees.
@@ -62,9 +350,51 @@ impl Display for AnonymousKey {
     }
 }
 
+/// The function to format a type-erased key with its [`Display`] implementation.
+type DisplayFn = fn(&dyn Any, &mut std::fmt::Formatter<'_>) -> std::fmt::Result;
+
+fn display_fn<K: Display + 'static>(
+    key: &dyn Any,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    Display::fmt(key.downcast_ref::<K>().unwrap(), f)
+}
+
+/// The [`DisplayFn`]s of key types, registered with [`AnyKey::register_display`].
+fn display_fns() -> &'static RwLock<HashMap<TypeId, DisplayFn>> {
+    static DISPLAY_FNS: OnceLock<RwLock<HashMap<TypeId, DisplayFn>>> = OnceLock::new();
+    DISPLAY_FNS.get_or_init(Default::default)
+}
+
+/// Resolve the [`DisplayFn`] of the key type `K`, if it's one of the built-in types or registered
+/// with [`AnyKey::register_display`].
+///
+/// Detecting whether `K` implements [`Display`] requires specialization, so other types have to
+/// be registered explicitly.
+fn resolve_display_fn<K: 'static>() -> Option<DisplayFn> {
+    let type_id = TypeId::of::<K>();
+    if type_id == TypeId::of::<String>() {
+        Some(display_fn::<String>)
+    } else if type_id == TypeId::of::<&str>() {
+        Some(display_fn::<&str>)
+    } else if type_id == TypeId::of::<Arc<str>>() {
+        Some(display_fn::<Arc<str>>)
+    } else if type_id == TypeId::of::<Box<str>>() {
+        Some(display_fn::<Box<str>>)
+    } else if type_id == TypeId::of::<AnonymousKey>() {
+        Some(display_fn::<AnonymousKey>)
+    } else {
+        display_fns().read().get(&type_id).copied()
+    }
+}
+
 /// Type-erased key for the [`Registry`].
+///
+/// The key is formatted with its [`Display`] implementation if its type is `String`, `&str`,
+/// `Arc<str>`, `Box<str>`, or registered with [`AnyKey::register_display`], otherwise with its
+/// [`Debug`] implementation.
 #[derive(Clone)]
-pub struct AnyKey(Arc<dyn ObjKey>);
+pub struct AnyKey(Arc<dyn ObjKey>, Option<DisplayFn>);
 
 impl PartialEq for AnyKey {
     fn eq(&self, other: &Self) -> bool {
@@ -88,25 +418,36 @@ impl Debug for AnyKey {
 
 impl Display for AnyKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // TODO: for all `impl Display`?
-        macro_rules! delegate_to_display {
-            ($($t:ty),* $(,)?) => {
-                $(
-                    if let Some(k) = self.as_any().downcast_ref::<$t>() {
-                        return write!(f, "{}", k);
-                    }
-                )*
-            };
+        match self.1 {
+            Some(display) => display(self.as_any(), f),
+            None => write!(f, "{:?}", self),
         }
-        delegate_to_display!(String, &str, AnonymousKey);
-
-        write!(f, "{:?}", self)
     }
 }
 
 impl AnyKey {
-    fn new(key: impl ObjKey) -> Self {
-        Self(Arc::new(key))
+    /// Create a key for looking up an await-tree, which is never formatted, so the display
+    /// function is not resolved.
+    fn new<K: ObjKey>(key: K) -> Self {
+        Self(Arc::new(key), None)
+    }
+
+    /// Create a key to be stored in the registry, with the display function resolved.
+    fn new_stored<K: ObjKey>(key: K) -> Self {
+        Self(Arc::new(key), resolve_display_fn::<K>())
+    }
+
+    /// Register the key type `K` to be formatted with its [`Display`] implementation, instead of
+    /// the [`Debug`] one, when formatting an [`AnyKey`] with [`Display`].
+    ///
+    /// The display function is resolved once when an await-tree is registered, so this only
+    /// affects the await-trees registered afterwards. It should be called on startup, before
+    /// spawning any task with keys of type `K`. String keys of type `String`, `&str`, `Arc<str>`
+    /// and `Box<str>` are always formatted with [`Display`] without registration.
+    pub fn register_display<K: Key + Display>() {
+        display_fns()
+            .write()
+            .insert(TypeId::of::<K>(), display_fn::<K>);
     }
 
     /// Cast the key to `dyn Any`.
@@ -121,6 +462,11 @@ impl AnyKey {
         self.as_any().is::<K>()
     }
 
+    /// Returns the name of the type of the key, as returned by [`std::any::type_name`].
+    pub fn type_name(&self) -> &'static str {
+        self.0.type_name()
+    }
+
     /// Returns whether the key corresponds to an anonymous await-tree.
     pub fn is_anonymous(&self) -> bool {
         self.as_any().is::<AnonymousKey>()
@@ -134,11 +480,49 @@ impl AnyKey {
     }
 }
 
+/// The error returned by [`Registry::try_register`] if the key already exists.
+#[derive(Debug, Clone)]
+pub struct DuplicateKeyError {
+    key: AnyKey,
+}
+
+impl DuplicateKeyError {
+    /// Returns the duplicate key.
+    pub fn key(&self) -> &AnyKey {
+        &self.key
+    }
+}
+
+impl Display for DuplicateKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "await-tree with key `{}` is already registered",
+            self.key
+        )
+    }
+}
+
+impl std::error::Error for DuplicateKeyError {}
+
 type Contexts = RwLock<WeakValueHashMap<AnyKey, Weak<TreeContext>>>;
 
+/// Remove the expired entries from the map by rebuilding it, with the capacity preserved.
+///
+/// Do not use `WeakValueHashMap::remove_expired`, which may skip entries after a removal.
+fn remove_expired(contexts: &mut WeakValueHashMap<AnyKey, Weak<TreeContext>>) {
+    let mut rebuilt = WeakValueHashMap::with_capacity(contexts.capacity());
+    rebuilt.extend(contexts.iter().map(|(k, v)| (k.clone(), v)));
+    *contexts = rebuilt;
+}
+
 struct RegistryCore {
+    id: u64,
     contexts: Contexts,
-    config: Config,
+    aborted: Mutex<VecDeque<(AnyKey, Tree)>>,
+    imported: RwLock<HashMap<AnyKey, Tree>>,
+    subscribers: Arc<Subscribers>,
+    config: RwLock<Config>,
 }
 
 /// The registry of multiple await-trees.
@@ -149,7 +533,7 @@ pub struct Registry(Arc<RegistryCore>);
 impl Debug for Registry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Registry")
-            .field("config", self.config())
+            .field("config", &*self.0.config.read())
             .finish_non_exhaustive()
     }
 }
@@ -165,23 +549,86 @@ impl Registry {
         &self.0.contexts
     }
 
-    fn config(&self) -> &Config {
-        &self.0.config
+    /// Returns the context of the await-tree with given key, if it exists and is not dropped.
+    pub(crate) fn context(&self, key: impl Key) -> Option<Arc<TreeContext>> {
+        self.context_of(&AnyKey::new(key))
+    }
+
+    /// Returns the context of the await-tree with given type-erased key.
+    fn context_of(&self, key: &AnyKey) -> Option<Arc<TreeContext>> {
+        self.contexts().read().get(key) // TODO: accept ref can?
+    }
+
+    pub(crate) fn subscribers(&self) -> &Arc<Subscribers> {
+        &self.0.subscribers
+    }
+
+    /// Returns a weak reference to the registry.
+    pub(crate) fn downgrade(&self) -> WeakRegistry {
+        WeakRegistry(Arc::downgrade(&self.0))
+    }
+
+    /// Call `f` on each await-tree of live tasks in the registry, without cloning the trees.
+    /// Imported trees are not included.
+    pub(crate) fn for_each_tree(&self, mut f: impl FnMut(&AnyKey, &Tree)) {
+        for (k, v) in self.contexts().read().iter() {
+            f(k, &v.tree());
+        }
+    }
+
+    /// Returns the unique id of the registry.
+    pub(crate) fn id(&self) -> u64 {
+        self.0.id
     }
 }
 
 impl Registry {
     /// Create a new registry with given `config`.
     pub fn new(config: Config) -> Self {
+        Self::new_inner(config, Default::default())
+    }
+
+    /// Create a new registry with given `config`, with the map of await-trees pre-sized to hold
+    /// at least `capacity` entries without rehashing.
+    ///
+    /// This smooths the startup of systems that spawn a large, known number of tasks at once.
+    pub fn with_capacity(config: Config, capacity: usize) -> Self {
+        Self::new_inner(config, WeakValueHashMap::with_capacity(capacity))
+    }
+
+    fn new_inner(config: Config, contexts: WeakValueHashMap<AnyKey, Weak<TreeContext>>) -> Self {
+        static ID: AtomicU64 = AtomicU64::new(0);
+        let id = ID.fetch_add(1, Ordering::Relaxed);
+        ANY_REGISTRY_CREATED.store(true, Ordering::Relaxed);
+
         Self(
             RegistryCore {
-                contexts: Default::default(),
-                config,
+                id,
+                contexts: RwLock::new(contexts),
+                aborted: Default::default(),
+                imported: Default::default(),
+                subscribers: Arc::new(Subscribers::new(config.subscriber_capacity)),
+                config: RwLock::new(config),
             }
             .into(),
         )
     }
 
+    /// Returns a clone of the current configuration of the registry.
+    pub fn config(&self) -> Config {
+        self.0.config.read().clone()
+    }
+
+    /// Replace the configuration of the registry at runtime, e.g., to turn on `verbose` during an
+    /// incident without restarting.
+    ///
+    /// The new configuration only applies to the await-trees registered afterwards. Existing
+    /// await-trees keep the configuration captured at registration. The capacity of subscribers
+    /// is also fixed at the creation of the registry and is not affected.
+    pub fn reconfigure(&self, config: Config) {
+        *self.0.config.write() = config;
+    }
+
     /// Returns the current registry, if exists.
     ///
     /// 1. If the current task is registered with a registry, returns the registry.
@@ -200,15 +647,38 @@ impl Registry {
         Self::try_current().expect("no current registry")
     }
 
-    fn register_inner(&self, key: impl Key, context: Arc<TreeContext>) -> TreeRoot {
-        self.contexts()
-            .write()
-            .insert(AnyKey::new(key), Arc::clone(&context));
+    /// Register the context with given key. If the key already exists, the old one is replaced if
+    /// `replace` is true, otherwise an error is returned.
+    fn register_inner(
+        &self,
+        key: impl Key,
+        mut context: TreeContext,
+        replace: bool,
+    ) -> Result<TreeRoot, DuplicateKeyError> {
+        let key = AnyKey::new_stored(key);
+        context.set_event_sink(key.clone(), self.subscribers().clone());
+        let context = Arc::new(context);
 
-        TreeRoot {
-            context,
-            registry: WeakRegistry(Arc::downgrade(&self.0)),
+        let mut contexts = self.contexts().write();
+        if !replace && contexts.get(&key).is_some() {
+            return Err(DuplicateKeyError { key });
+        }
+        if self.0.config.read().auto_gc {
+            remove_expired(&mut contexts);
         }
+        contexts.insert(key.clone(), Arc::clone(&context));
+        drop(contexts);
+
+        let on_register = self.0.config.read().on_register.clone();
+        if let Some(callback) = on_register {
+            callback(&key);
+        }
+
+        Ok(TreeRoot {
+            context,
+            key,
+            registry: self.downgrade(),
+        })
     }
 
     /// Register with given key. Returns a [`TreeRoot`] that can be used to instrument a future.
@@ -216,8 +686,62 @@ impl Registry {
     /// If the key already exists, a new [`TreeRoot`] is returned and the reference to the old
     /// [`TreeRoot`] is dropped.
     pub fn register(&self, key: impl Key, root_span: impl Into<Span>) -> TreeRoot {
-        let context = Arc::new(TreeContext::new(root_span.into(), self.config().verbose));
-        self.register_inner(key, context)
+        let context = TreeContext::new(root_span.into(), &self.0.config.read());
+        self.register_inner(key, context, true)
+            .expect("should not fail when replacing")
+    }
+
+    /// Register with given key. Returns a [`TreeRoot`] that can be used to instrument a future.
+    ///
+    /// Unlike [`Registry::register`], returns an error if the key already exists and the tree root
+    /// is not dropped yet. This helps to catch bugs of key collision early.
+    pub fn try_register(
+        &self,
+        key: impl Key,
+        root_span: impl Into<Span>,
+    ) -> Result<TreeRoot, DuplicateKeyError> {
+        let context = TreeContext::new(root_span.into(), &self.0.config.read());
+        self.register_inner(key, context, false)
+    }
+
+    /// Register with given key if it's absent. Returns a [`TreeRoot`] that can be used to
+    /// instrument a future, or `None` if the key already exists and the tree root is not dropped
+    /// yet.
+    ///
+    /// This is useful for re-entrant setup code that may register the same logical task twice,
+    /// where the existing await-tree should be kept instead of being replaced like
+    /// [`Registry::register`] does. Note that the existing [`TreeRoot`] cannot be returned, as it's
+    /// owned by the future it instruments.
+    pub fn register_if_absent(
+        &self,
+        key: impl Key,
+        root_span: impl Into<Span>,
+    ) -> Option<TreeRoot> {
+        self.try_register(key, root_span).ok()
+    }
+
+    /// Register with given key as a child task of the await-tree with `parent_key`. Returns a
+    /// [`TreeRoot`] that can be used to instrument a future.
+    ///
+    /// This is useful when a task is spawned to do the work of another one, whose await-trees are
+    /// otherwise unrelated. A link to the child is added under the current span of the parent
+    /// tree, shown as `-> [key]` in its output, so that the await-tree of the child can be looked
+    /// up with the key. The link is removed when the child await-tree is dropped.
+    ///
+    /// If the parent does not exist, this is equivalent to [`Registry::register`].
+    pub fn register_child(
+        &self,
+        parent_key: impl Key,
+        key: impl Key,
+        root_span: impl Into<Span>,
+    ) -> TreeRoot {
+        let parent = self.context(parent_key);
+        let root = self.register(key, root_span);
+        if let Some(parent) = parent {
+            root.context
+                .link_to_parent(&parent, root.key.to_string().into());
+        }
+        root
     }
 
     /// Register an anonymous await-tree without specifying a key. Returns a [`TreeRoot`] that can
@@ -228,23 +752,218 @@ impl Registry {
     // TODO: we have keyed and anonymous, should we also have a typed-anonymous (for classification
     // only)?
     pub fn register_anonymous(&self, root_span: impl Into<Span>) -> TreeRoot {
-        let context = Arc::new(TreeContext::new(root_span.into(), self.config().verbose));
-        self.register_inner(AnonymousKey(context.id()), context) // use the private id as the key
+        let context = TreeContext::new(root_span.into(), &self.0.config.read());
+        // use the private id as the key
+        self.register_inner(AnonymousKey(context.id()), context, true)
+            .expect("should not fail when replacing")
     }
 
-    /// Get a clone of the await-tree with given key.
+    /// Get a clone of the await-tree with given key, either registered or imported.
     ///
     /// Returns `None` if the key does not exist or the tree root has been dropped.
     pub fn get(&self, key: impl Key) -> Option<Tree> {
-        self.contexts()
-            .read()
-            .get(&AnyKey::new(key)) // TODO: accept ref can?
-            .map(|v| v.tree().clone())
+        self.with_tree(key, Tree::clone)
+    }
+
+    /// Run the given closure against the await-tree with given key, without cloning it.
+    ///
+    /// The tree is locked during the call of `f`, which blocks the progress of the task. So `f`
+    /// should be cheap, like formatting the tree or counting the spans.
+    ///
+    /// The registered await-tree takes precedence over the imported one with the same key.
+    /// Returns `None` if the key does not exist or the tree root has been dropped.
+    pub fn with_tree<R>(&self, key: impl Key, f: impl FnOnce(&Tree) -> R) -> Option<R> {
+        let key = AnyKey::new(key);
+        if let Some(context) = self.context_of(&key) {
+            let tree = context.tree();
+            return Some(f(&tree));
+        }
+        self.0.imported.read().get(&key).map(f)
     }
 
-    /// Remove all the registered await-trees.
+    /// Remove all the registered and imported await-trees.
     pub fn clear(&self) {
         self.contexts().write().clear();
+        self.0.imported.write().clear();
+    }
+
+    /// Remove the registered and imported await-trees registered with
+    /// [`Registry::register_anonymous`], keeping the ones with keys.
+    pub fn clear_anonymous(&self) {
+        self.remove_keys(AnyKey::is_anonymous);
+    }
+
+    /// Remove the registered and imported await-trees with the key of type `K`.
+    pub fn clear_type<K: Key>(&self) {
+        self.remove_keys(AnyKey::is::<K>);
+    }
+
+    /// Remove the registered and imported await-trees whose key matches the predicate, evaluated
+    /// under the write lock of the registry.
+    fn remove_keys(&self, f: impl Fn(&AnyKey) -> bool) {
+        let mut contexts = self.contexts().write();
+        // Do not use `WeakValueHashMap::retain`, which may skip entries after a removal.
+        let to_remove = contexts
+            .keys()
+            .filter(|k| f(k))
+            .cloned()
+            .collect::<Vec<_>>();
+        for k in to_remove {
+            contexts.remove(&k);
+        }
+        drop(contexts);
+
+        self.0.imported.write().retain(|k, _| !f(k));
+    }
+
+    /// Import the snapshots of await-trees, for example, collected from other registries with
+    /// [`Registry::collect_all`], to get a consolidated view of multiple registries.
+    ///
+    /// The imported trees have no live task behind, so they're frozen at the time of import.
+    /// They're visible to [`Registry::get`], [`Registry::with_tree`], [`Registry::retain`], the
+    /// `collect` family and [`Registry::total_elapsed_by_category`] like the registered ones,
+    /// until replaced by another import with the same key or removed with [`Registry::retain`] or
+    /// [`Registry::clear`]. As there are no more changes, they're not watched by
+    /// [`Registry::spawn_watchdog`], [`Registry::subscribe`] or [`Registry::watch_key`].
+    pub fn import(&self, entries: impl IntoIterator<Item = (AnyKey, Tree)>) {
+        let mut imported = self.0.imported.write();
+        for (key, mut tree) in entries {
+            tree.freeze();
+            imported.insert(key, tree);
+        }
+    }
+
+    /// Remove the entries of dropped await-trees from the registry.
+    ///
+    /// This takes time linear in the number of entries under the write lock of the registry. See
+    /// [`ConfigBuilder::auto_gc`] for more information.
+    pub fn gc(&self) {
+        remove_expired(&mut self.contexts().write());
+    }
+
+    /// Retain only the await-trees specified by the predicate, removing the others.
+    ///
+    /// The predicate is evaluated against each tree under the write lock of the registry, so it's
+    /// atomic with respect to concurrent registrations. Imported trees are evaluated afterwards.
+    pub fn retain(&self, mut f: impl FnMut(&AnyKey, &Tree) -> bool) {
+        let mut contexts = self.contexts().write();
+        // Do not use `WeakValueHashMap::retain`, which may skip entries after a removal.
+        let to_remove = contexts
+            .iter()
+            .filter(|(k, v)| !f(k, &v.tree()))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        for k in to_remove {
+            contexts.remove(&k);
+        }
+        drop(contexts);
+
+        self.0.imported.write().retain(|k, v| f(k, v));
+    }
+
+    /// Record the snapshot of the await-tree of an aborted task, taken with `snapshot` only if
+    /// the recording is enabled.
+    pub(crate) fn record_aborted(&self, key: AnyKey, snapshot: impl FnOnce() -> Tree) {
+        let capacity = self.0.config.read().aborted_capacity;
+        if capacity == 0 {
+            return;
+        }
+        let tree = snapshot();
+        let mut aborted = self.0.aborted.lock();
+        if aborted.len() >= capacity {
+            aborted.pop_front();
+        }
+        aborted.push_back((key, tree));
+    }
+
+    /// Returns the snapshots of the await-trees of recently aborted tasks, from the oldest to the
+    /// newest.
+    ///
+    /// The snapshot is taken right before the task is dropped, with the elapsed time of spans
+    /// frozen at that moment. At most [`ConfigBuilder::aborted_capacity`] snapshots are kept.
+    pub fn recent_aborted(&self) -> Vec<(AnyKey, Tree)> {
+        self.0.aborted.lock().iter().cloned().collect()
+    }
+
+    /// Returns the total elapsed time of the leaf spans of all await-trees, grouped by the category
+    /// set with [`SpanExt::category`](crate::SpanExt::category).
+    ///
+    /// This gives a cheap breakdown of where the tasks are spending time. Spans without a category
+    /// are not counted.
+    pub fn total_elapsed_by_category(&self) -> HashMap<&'static str, Duration> {
+        let mut total = HashMap::new();
+        let mut add = |tree: &Tree| {
+            for (node, elapsed) in tree.leaves() {
+                if let Some(category) = tree.span(node).category {
+                    *total.entry(category).or_default() += elapsed;
+                }
+            }
+        };
+        self.for_each_tree(|_, tree| add(tree));
+        self.0.imported.read().values().for_each(add);
+        total
+    }
+
+    /// Returns the total count of active span nodes across all await-trees, including the detached
+    /// ones.
+    ///
+    /// This is much cheaper than summing over [`Registry::collect_all`] as no tree is cloned, which
+    /// makes it suitable as a frequently scraped gauge. Note that each tree is still briefly locked
+    /// while its nodes are counted. Imported trees are not counted.
+    pub fn total_active_spans(&self) -> usize {
+        let mut total = 0;
+        self.for_each_tree(|_, tree| total += tree.active_node_count());
+        total
+    }
+
+    /// Returns the count of live await-trees for each type of keys, keyed by the name of the type
+    /// as returned by [`AnyKey::type_name`].
+    ///
+    /// No tree is locked or cloned, so this is cheap enough to be scraped frequently, which helps
+    /// spot the key types whose await-trees are leaking. Imported trees are not counted.
+    pub fn count_by_type(&self) -> HashMap<&'static str, usize> {
+        let mut counts = HashMap::new();
+        for (k, _) in self.contexts().read().iter() {
+            *counts.entry(k.type_name()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Collect the snapshots of the `n` slowest await-trees ranked by the given metric, slowest
+    /// first.
+    ///
+    /// Only the selected trees are cloned, which is much cheaper than sorting the result of
+    /// [`Registry::collect_all`] when there are a huge number of trees. Imported trees are not
+    /// ranked, as they have no live task behind.
+    pub fn slowest(&self, n: usize, by: SlowestBy) -> Vec<(AnyKey, Tree)> {
+        let mut ranked = self
+            .contexts()
+            .read()
+            .iter()
+            .map(|(k, v)| {
+                let tree = v.tree();
+                let elapsed = match by {
+                    SlowestBy::RootElapsed => tree.root_elapsed(),
+                    SlowestBy::MaxLeafElapsed => tree
+                        .leaves()
+                        .map(|(_, elapsed)| elapsed)
+                        .max()
+                        .unwrap_or_else(|| tree.root_elapsed()),
+                };
+                drop(tree);
+                (Reverse(elapsed), k.clone(), v)
+            })
+            .collect::<Vec<_>>();
+
+        if n < ranked.len() {
+            ranked.select_nth_unstable_by_key(n, |(elapsed, _, _)| *elapsed);
+            ranked.truncate(n);
+        }
+        ranked.sort_unstable_by_key(|(elapsed, _, _)| *elapsed);
+        ranked
+            .into_iter()
+            .map(|(_, k, v)| (k, v.tree().clone()))
+            .collect()
     }
 
     /// Collect the snapshots of all await-trees with the key of type `K`.
@@ -258,34 +977,124 @@ impl Registry {
                     .downcast_ref::<K>()
                     .map(|k| (k.clone(), v.tree().clone()))
             })
+            .chain(
+                self.0
+                    .imported
+                    .read()
+                    .iter()
+                    .filter_map(|(k, v)| k.downcast_ref::<K>().map(|k| (k.clone(), v.clone()))),
+            )
             .collect()
     }
 
     /// Collect the snapshots of all await-trees registered with [`Registry::register_anonymous`].
     pub fn collect_anonymous(&self) -> Vec<Tree> {
-        self.contexts()
-            .read()
-            .iter()
-            .filter_map(|(k, v)| {
-                if k.is_anonymous() {
-                    Some(v.tree().clone())
-                } else {
-                    None
-                }
-            })
-            .collect()
+        let mut buf = Vec::new();
+        self.collect_anonymous_into(&mut buf);
+        buf
+    }
+
+    /// Like [`Registry::collect_anonymous`], but clears and refills the given buffer instead of
+    /// allocating a new one, which reduces allocations for frequent collecting.
+    pub fn collect_anonymous_into(&self, buf: &mut Vec<Tree>) {
+        buf.clear();
+        buf.extend(self.contexts().read().iter().filter_map(|(k, v)| {
+            if k.is_anonymous() {
+                Some(v.tree().clone())
+            } else {
+                None
+            }
+        }));
+        buf.extend(
+            self.0
+                .imported
+                .read()
+                .iter()
+                .filter(|(k, _)| k.is_anonymous())
+                .map(|(_, v)| v.clone()),
+        );
     }
 
     /// Collect the snapshots of all await-trees regardless of the key type.
     pub fn collect_all(&self) -> Vec<(AnyKey, Tree)> {
-        self.contexts()
+        let mut buf = Vec::new();
+        self.collect_all_into(&mut buf);
+        buf
+    }
+
+    /// Like [`Registry::collect_all`], but clears and refills the given buffer instead of
+    /// allocating a new one, which reduces allocations for frequent collecting.
+    pub fn collect_all_into(&self, buf: &mut Vec<(AnyKey, Tree)>) {
+        buf.clear();
+        buf.extend(
+            self.contexts()
+                .read()
+                .iter()
+                .map(|(k, v)| (k.clone(), v.tree().clone())),
+        );
+        buf.extend(
+            self.0
+                .imported
+                .read()
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone())),
+        );
+    }
+
+    /// Collect the snapshots of the await-trees that satisfy the predicate, regardless of the key
+    /// type.
+    ///
+    /// The predicate is evaluated against the borrowed tree, so only the trees that pass are
+    /// cloned, which is cheaper than filtering the result of [`Registry::collect_all`].
+    pub fn collect_where(&self, pred: impl Fn(&AnyKey, &Tree) -> bool) -> Vec<(AnyKey, Tree)> {
+        let mut buf = Vec::new();
+        for (k, v) in self.contexts().read().iter() {
+            let tree = v.tree();
+            if pred(k, &tree) {
+                buf.push((k.clone(), tree.clone()));
+            }
+        }
+        buf.extend(
+            self.0
+                .imported
+                .read()
+                .iter()
+                .filter(|(k, v)| pred(k, v))
+                .map(|(k, v)| (k.clone(), v.clone())),
+        );
+        buf
+    }
+
+    /// Write the dump of all await-trees, including the imported ones, to the given writer, in the
+    /// form of `[key]` followed by the tree for each await-tree.
+    ///
+    /// Unlike formatting the trees from [`Registry::collect_all`], at most one tree is cloned at a
+    /// time, so the whole dump is never materialized in memory. The lock of the registry is only
+    /// held to list the await-trees, not during writing.
+    pub fn dump_all(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
+        let contexts = self
+            .contexts()
             .read()
             .iter()
-            .map(|(k, v)| (k.clone(), v.tree().clone()))
-            .collect()
+            .map(|(k, v)| (k.clone(), v))
+            .collect::<Vec<_>>();
+        for (key, context) in contexts {
+            let tree = context.tree().clone();
+            writeln!(w, "[{key}]\n{tree}")?;
+        }
+
+        let imported = self.0.imported.read().keys().cloned().collect::<Vec<_>>();
+        for key in imported {
+            let tree = self.0.imported.read().get(&key).cloned();
+            if let Some(tree) = tree {
+                writeln!(w, "[{key}]\n{tree}")?;
+            }
+        }
+        Ok(())
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct WeakRegistry(Weak<RegistryCore>);
 
 impl WeakRegistry {
@@ -296,6 +1105,8 @@ impl WeakRegistry {
 
 #[cfg(test)]
 mod tests {
+    use itertools::Itertools;
+
     use super::*;
 
     #[test]
@@ -330,4 +1141,324 @@ mod tests {
         let all = registry.collect_all();
         assert_eq!(all.len(), 8);
     }
+
+    #[test]
+    fn test_with_tree() {
+        let registry = Registry::new(Config::default());
+        let _root = registry.register(1_i32, "one");
+
+        let path = registry.with_tree(1_i32, |tree| tree.current_path());
+        assert_eq!(path.unwrap(), registry.get(1_i32).unwrap().current_path());
+        assert!(registry.with_tree(2_i32, |_| ()).is_none());
+    }
+
+    #[test]
+    fn test_try_register() {
+        let registry = Registry::new(Config::default());
+
+        let root = registry.try_register(1_i32, "1").unwrap();
+        let Err(err) = registry.try_register(1_i32, "1 again") else {
+            panic!("duplicate key should be rejected");
+        };
+        assert_eq!(err.key().downcast_ref::<i32>(), Some(&1));
+        assert_eq!(registry.get(1_i32).unwrap().current_path(), ["1".into()]);
+
+        drop(root);
+        let _root = registry.try_register(1_i32, "1 again").unwrap();
+    }
+
+    #[test]
+    fn test_retain() {
+        let registry = Registry::new(Config::default());
+
+        let _0_i32 = registry.register(0_i32, "0");
+        let _1_i32 = registry.register(1_i32, "1");
+        let _0_str = registry.register("0", "0");
+        let _anon = registry.register_anonymous("anon");
+        let _anon = registry.register_anonymous("anon");
+
+        registry.retain(|k, _| !k.is_anonymous());
+        assert_eq!(registry.collect_anonymous().len(), 0);
+        assert_eq!(registry.collect_all().len(), 3);
+
+        registry.retain(|_, tree| tree.to_string().starts_with('1'));
+        let all = registry.collect_all();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].0.downcast_ref::<i32>(), Some(&1));
+    }
+
+    #[test]
+    fn test_gc() {
+        let registry = Registry::new(Config::default());
+        for i in 0..10_i32 {
+            let _root = registry.register(i, "dropped");
+        }
+        let _root = registry.register(10_i32, "alive");
+
+        registry.gc();
+        assert_eq!(registry.contexts().read().len(), 1);
+        assert_eq!(registry.collect_all().len(), 1);
+
+        let registry = Registry::new(ConfigBuilder::default().auto_gc(true).build().unwrap());
+        for i in 0..10_i32 {
+            let _root = registry.register(i, "dropped");
+        }
+        assert_eq!(registry.contexts().read().len(), 1);
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let registry = Registry::new(Config::default());
+        let verbose = |key: i32| {
+            let context = registry.contexts().read().get(&AnyKey::new(key)).unwrap();
+            context.verbose()
+        };
+
+        let _root_1 = registry.register(1_i32, "1");
+        assert!(!registry.config().verbose);
+
+        registry.reconfigure(ConfigBuilder::default().verbose(true).build().unwrap());
+        assert!(registry.config().verbose);
+        let _root_2 = registry.register(2_i32, "2");
+
+        // Existing trees keep the captured configuration.
+        assert!(!verbose(1));
+        assert!(verbose(2));
+    }
+
+    #[test]
+    fn test_collect_into() {
+        let registry = Registry::new(Config::default());
+        let _1_i32 = registry.register(1_i32, "1");
+        let _anon = registry.register_anonymous("anon");
+
+        let mut all = Vec::new();
+        let mut anons = Vec::new();
+        for _ in 0..2 {
+            registry.collect_all_into(&mut all);
+            registry.collect_anonymous_into(&mut anons);
+            assert_eq!(all.len(), 2);
+            assert_eq!(anons.len(), 1);
+        }
+
+        let capacity = all.capacity();
+        registry.clear();
+        registry.collect_all_into(&mut all);
+        assert!(all.is_empty());
+        assert_eq!(all.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let registry = Registry::with_capacity(Config::default(), 1000);
+        assert!(registry.contexts().read().capacity() >= 1000);
+
+        let _root = registry.register(1_i32, "1");
+        registry.gc();
+        assert!(registry.contexts().read().capacity() >= 1000);
+        assert!(registry.get(1_i32).is_some());
+    }
+
+    #[test]
+    fn test_collect_where() {
+        let registry = Registry::new(Config::default());
+        let _1_i32 = registry.register(1_i32, "1");
+        let _2_i32 = registry.register(2_i32, "2");
+        let _anon = registry.register_anonymous("anon");
+
+        let odds =
+            registry.collect_where(|k, _| k.downcast_ref::<i32>().is_some_and(|k| k % 2 == 1));
+        assert_eq!(odds.len(), 1);
+        assert_eq!(odds[0].0.downcast_ref::<i32>(), Some(&1));
+
+        let anons = registry.collect_where(|_, tree| tree.to_string().starts_with("anon"));
+        assert_eq!(anons.len(), 1);
+        assert!(anons[0].0.is_anonymous());
+    }
+
+    #[test]
+    fn test_total_active_spans() {
+        let registry = Registry::new(Config::default());
+        assert_eq!(registry.total_active_spans(), 0);
+
+        let _1_i32 = registry.register(1_i32, "1");
+        let _2_i32 = registry.register(2_i32, "2");
+        assert_eq!(registry.total_active_spans(), 2);
+    }
+
+    #[test]
+    fn test_clear_anonymous_and_type() {
+        let registry = Registry::new(Config::default());
+
+        let _1_i32 = registry.register(1_i32, "1");
+        let _foo = registry.register("foo", "foo");
+        let _anonymous = registry.register_anonymous("anonymous");
+        registry.import([(AnyKey::new_stored(2_i32), registry.get(1_i32).unwrap())]);
+
+        registry.clear_anonymous();
+        assert!(registry.collect_anonymous().is_empty());
+        assert_eq!(registry.collect_all().len(), 3);
+
+        registry.clear_type::<i32>();
+        assert!(registry.collect::<i32>().is_empty());
+        assert_eq!(registry.collect::<&str>().len(), 1);
+    }
+
+    #[test]
+    fn test_on_register() {
+        let registry = Arc::new(OnceLock::<Registry>::new());
+        let registered = Arc::new(Mutex::new(Vec::new()));
+        let config = ConfigBuilder::default()
+            .on_register({
+                let registry = registry.clone();
+                let registered = registered.clone();
+                Arc::new(move |key: &AnyKey| {
+                    // Accessing the registry in the callback should not deadlock.
+                    let count = registry
+                        .get()
+                        .unwrap()
+                        .count_by_type()
+                        .values()
+                        .sum::<usize>();
+                    registered.lock().push((key.to_string(), count));
+                })
+            })
+            .build()
+            .unwrap();
+        let registry = registry.get_or_init(|| Registry::new(config));
+
+        let _foo = registry.register("foo", "foo");
+        let _bar = registry.try_register("bar", "bar").unwrap();
+        assert!(registry.try_register("bar", "bar").is_err());
+        let _anonymous = registry.register_anonymous("anonymous");
+
+        let registered = registered.lock().clone();
+        assert_eq!(registered.len(), 3);
+        assert_eq!(registered[0], ("foo".to_owned(), 1));
+        assert_eq!(registered[1], ("bar".to_owned(), 2));
+        assert_eq!(registered[2].1, 3);
+    }
+
+    #[test]
+    fn test_count_by_type() {
+        let registry = Registry::new(Config::default());
+        assert!(registry.count_by_type().is_empty());
+
+        let _1_i32 = registry.register(1_i32, "1");
+        let _2_i32 = registry.register(2_i32, "2");
+        let _foo = registry.register("foo", "foo");
+        let anonymous = registry.register_anonymous("anonymous");
+        drop(anonymous);
+
+        let counts = registry.count_by_type();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[std::any::type_name::<i32>()], 2);
+        assert_eq!(counts[std::any::type_name::<&str>()], 1);
+    }
+
+    #[test]
+    fn test_register_if_absent() {
+        let registry = Registry::new(Config::default());
+
+        let first = registry.register_if_absent(1_i32, "first").unwrap();
+        assert!(registry.register_if_absent(1_i32, "second").is_none());
+        assert!(registry
+            .get(1_i32)
+            .unwrap()
+            .to_string()
+            .starts_with("first"));
+
+        drop(first);
+        let _second = registry.register_if_absent(1_i32, "second").unwrap();
+        assert!(registry
+            .get(1_i32)
+            .unwrap()
+            .to_string()
+            .starts_with("second"));
+    }
+
+    #[test]
+    fn test_owned_keys() {
+        let registry = Registry::new(Config::default());
+        let name: Arc<str> = "actor".into();
+        let _arc = registry.register(name.clone(), "arc");
+        let _boxed = registry.register(Box::<str>::from("boxed"), "boxed");
+        let _composite = registry.register((name.clone(), 1_u32), "composite");
+
+        assert!(registry.get(name.clone()).is_some());
+        assert!(registry.get(Box::<str>::from("boxed")).is_some());
+        assert!(registry.get((name, 1_u32)).is_some());
+
+        let keys = registry
+            .collect_all()
+            .into_iter()
+            .map(|(k, _)| k.to_string())
+            .sorted()
+            .collect_vec();
+        assert_eq!(keys, ["(\"actor\", 1)", "actor", "boxed"]);
+    }
+
+    #[test]
+    fn test_register_display() {
+        #[derive(Debug, PartialEq, Eq, Hash)]
+        struct Actor(u32);
+
+        impl Display for Actor {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "actor #{}", self.0)
+            }
+        }
+
+        assert_eq!(AnyKey::new_stored("str").to_string(), "str");
+        assert_eq!(AnyKey::new_stored(Actor(1)).to_string(), "Actor(1)");
+
+        AnyKey::register_display::<Actor>();
+        assert_eq!(AnyKey::new_stored(Actor(1)).to_string(), "actor #1");
+    }
+
+    #[test]
+    fn test_import() {
+        let other = Registry::new(Config::default());
+        let _root = other.register(1_i32, "other");
+        let _anon = other.register_anonymous("other anon");
+
+        let registry = Registry::new(Config::default());
+        let _root = registry.register(2_i32, "this");
+        registry.import(other.collect_all());
+
+        assert_eq!(registry.collect_all().len(), 3);
+        assert_eq!(registry.collect_anonymous().len(), 1);
+        let i32s = registry.collect::<i32>();
+        let imported = i32s.iter().find(|(k, _)| *k == 1).unwrap();
+        assert_eq!(imported.1.current_path(), ["other".into()]);
+
+        // Imported trees are frozen.
+        let before = imported.1.to_string();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(imported.1.to_string(), before);
+
+        // Imported trees can be looked up and removed like the registered ones.
+        let path = registry.with_tree(1_i32, |tree| tree.current_path());
+        assert_eq!(path, Some(vec!["other".into()]));
+        registry.retain(|k, _| k.downcast_ref::<i32>() != Some(&1));
+        assert!(registry.get(1_i32).is_none());
+        assert_eq!(registry.collect_all().len(), 2);
+
+        registry.clear();
+        assert!(registry.collect_all().is_empty());
+    }
+
+    #[test]
+    fn test_dump_all() {
+        let registry = Registry::new(Config::default());
+        let _root = registry.register("task", "root");
+        registry.import([(AnyKey::new_stored(1_i32), registry.get("task").unwrap())]);
+
+        let mut out = Vec::new();
+        registry.dump_all(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("[task]\nroot ["), "{out}");
+        assert!(out.contains("[1]\nroot ["), "{out}");
+        assert_eq!(out.lines().count(), 6, "{out}");
+    }
 }
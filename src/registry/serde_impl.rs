@@ -0,0 +1,81 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming serialization of all await-trees in a [`Registry`].
+
+use itertools::Itertools;
+use serde::ser::{SerializeSeq, SerializeStruct};
+use serde::{Serialize, Serializer};
+
+use super::{AnyKey, Registry};
+use crate::Tree;
+
+/// An entry of the serialized registry, with the key formatted with its [`Display`] implementation
+/// as in [`AnyKey`].
+///
+/// [`Display`]: std::fmt::Display
+struct Entry<'a> {
+    key: &'a AnyKey,
+    tree: &'a Tree,
+}
+
+impl Serialize for Entry<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entry = serializer.serialize_struct("Entry", 2)?;
+        entry.serialize_field("key", &format_args!("{}", self.key))?;
+        entry.serialize_field("tree", self.tree)?;
+        entry.end()
+    }
+}
+
+impl Registry {
+    /// Serialize all await-trees in this registry as a sequence of `{ "key": .., "tree": .. }`
+    /// entries with the given serializer, where the key is formatted with [`Display`] and the
+    /// tree is in the format of [`Tree`]'s `Serialize` implementation.
+    ///
+    /// Unlike serializing the result of [`Registry::collect_all`], the trees are serialized one by
+    /// one without being cloned, so passing a streaming serializer like the one of
+    /// `serde_json::to_writer` writes the entries directly to a socket or a file, without building
+    /// the whole snapshot in memory. This is preferable for registries with a huge number of
+    /// trees.
+    ///
+    /// The read lock of the registry is only held briefly for listing the live await-trees, while
+    /// each tree is locked only during its own serialization, so a slow writer does not block the
+    /// registration of new trees, but blocks the task of the tree being written on its next
+    /// poll. The imported trees are serialized last, holding the read lock of the imported trees
+    /// until the end.
+    ///
+    /// [`Display`]: std::fmt::Display
+    pub fn serialize_stream<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let contexts = self
+            .contexts()
+            .read()
+            .iter()
+            .map(|(k, v)| (k.clone(), v))
+            .collect_vec();
+        let imported = self.0.imported.read();
+
+        let mut seq = serializer.serialize_seq(Some(contexts.len() + imported.len()))?;
+        for (key, context) in &contexts {
+            seq.serialize_element(&Entry {
+                key,
+                tree: &context.tree(),
+            })?;
+        }
+        for (key, tree) in imported.iter() {
+            seq.serialize_element(&Entry { key, tree })?;
+        }
+        seq.end()
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use pin_project::pin_project;
+
+use crate::context::SpanHandle;
+use crate::root::current_context;
+
+/// The future for [`with_parent_span`].
+#[pin_project]
+pub struct WithParentSpan<F> {
+    #[pin]
+    inner: F,
+    parent: SpanHandle,
+}
+
+/// Poll the given future with its spans attached under the given `parent` span, instead of the
+/// span of the future actually polling it.
+///
+/// By default, the span of an instrumented future is attached under the span of its poller, and is
+/// remounted automatically if it's polled by another future later. This allows modeling the work
+/// that migrates between logical owners, by naming the parent explicitly.
+///
+/// The remount is rejected with a warning if the `parent` span belongs to a different await-tree,
+/// has been closed, or is the span of the future itself or one of its descendants, in which case
+/// the future is polled as usual.
+pub fn with_parent_span<F: Future>(parent: SpanHandle, future: F) -> WithParentSpan<F> {
+    WithParentSpan {
+        inner: future,
+        parent,
+    }
+}
+
+impl<F: Future> Future for WithParentSpan<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        let context = match current_context() {
+            // Context correct
+            Some(c) if c.id() == this.parent.context_id => c,
+            // Context changed
+            Some(_) => {
+                tracing::warn!("cannot remount a future under a span of a different context");
+                return this.inner.poll(cx);
+            }
+            // Out of context
+            None => return this.inner.poll(cx),
+        };
+
+        let Some(saved) = context.tree().enter_at(this.parent.node) else {
+            tracing::warn!("cannot remount a future under a closed span");
+            return this.inner.poll(cx);
+        };
+        let output = this.inner.poll(cx);
+        context.tree().restore_current(saved);
+        output
+    }
+}
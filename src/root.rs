@@ -13,16 +13,21 @@
 // limitations under the License.
 
 use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use pin_project::{pin_project, pinned_drop};
 
 use crate::context::TreeContext;
 use crate::global::global_registry;
 use crate::registry::WeakRegistry;
-use crate::Registry;
+use crate::{AnyKey, Registry};
 
 /// The root of an await-tree.
 pub struct TreeRoot {
     pub(crate) context: Arc<TreeContext>,
+    pub(crate) key: AnyKey,
     pub(crate) registry: WeakRegistry,
 }
 
@@ -43,7 +48,78 @@ pub(crate) fn current_registry() -> Option<Registry> {
 
 impl TreeRoot {
     /// Instrument the given future with the context of this tree root.
+    ///
+    /// If the returned future is dropped before completion, a snapshot of the await-tree is
+    /// recorded in the registry. See [`Registry::recent_aborted`] for more information.
     pub async fn instrument<F: Future>(self, future: F) -> F::Output {
-        ROOT.scope(self, future).await
+        self.into_instrumented(future).await
+    }
+
+    /// Instrument the given future with the context of this tree root, like
+    /// [`TreeRoot::instrument`], but returns the wrapping future eagerly.
+    ///
+    /// The context is carried in the state of the returned future and re-established as the
+    /// task-local context on each poll, then reset after the poll. So it does not rely on any
+    /// outer scope of the executor, and can be used on executors that poll futures outside of
+    /// tokio, at the cost of swapping a thread-local on every poll.
+    pub fn into_instrumented<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+        SnapshotOnAbort {
+            context: self.context.clone(),
+            key: self.key.clone(),
+            registry: self.registry.clone(),
+            polled: false,
+            ready: false,
+            inner: ROOT.scope(self, future),
+        }
+    }
+}
+
+/// The future that records a snapshot of the await-tree if it's dropped before completion.
+#[pin_project(PinnedDrop)]
+struct SnapshotOnAbort<F> {
+    context: Arc<TreeContext>,
+    key: AnyKey,
+    registry: WeakRegistry,
+    polled: bool,
+    ready: bool,
+    #[pin]
+    inner: F,
+}
+
+impl<F: Future> Future for SnapshotOnAbort<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if !*this.polled {
+            *this.polled = true;
+            if let Some(id) = tokio::task::try_id() {
+                this.context.set_task_id(id);
+            }
+        }
+        let output = this.inner.poll(cx);
+        if output.is_ready() {
+            *this.ready = true;
+            this.context.check_collapsed();
+        }
+        output
+    }
+}
+
+#[pinned_drop]
+impl<F> PinnedDrop for SnapshotOnAbort<F> {
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        if *this.ready {
+            return;
+        }
+        // Take the snapshot before the inner future is dropped, which removes its spans.
+        if let Some(registry) = this.registry.upgrade() {
+            registry.record_aborted(this.key.clone(), || {
+                let mut tree = this.context.tree().clone();
+                tree.freeze();
+                tree
+            });
+        }
     }
 }
@@ -19,9 +19,10 @@
 
 use std::future::Future;
 
+use tokio::runtime::Handle;
 use tokio::task::JoinHandle;
 
-use crate::{Key, Registry, Span};
+use crate::{Key, Registry, Span, ToRootSpan};
 
 /// Spawns a new asynchronous task instrumented with the given root [`Span`], returning a
 /// [`JoinHandle`] for it.
@@ -29,15 +30,40 @@ use crate::{Key, Registry, Span};
 /// The spawned task will be registered in the current [`Registry`](crate::Registry) returned by
 /// [`Registry::try_current`] with the given [`Key`], if it exists. Otherwise, this is equivalent to
 /// [`tokio::spawn`].
+///
+/// This is a shorthand for [`spawn_on`] with the handle of the current runtime.
+///
+/// # Panics
+///
+/// Panics if called outside of a Tokio runtime.
 pub fn spawn<T>(key: impl Key, root_span: impl Into<Span>, future: T) -> JoinHandle<T::Output>
+where
+    T: Future + Send + 'static,
+    T::Output: Send + 'static,
+{
+    spawn_on(&Handle::current(), key, root_span, future)
+}
+
+/// Spawns a new asynchronous task on the runtime of the given [`Handle`], instrumented with the
+/// given root [`Span`], returning a [`JoinHandle`] for it.
+///
+/// The spawned task will be registered in the current [`Registry`](crate::Registry) returned by
+/// [`Registry::try_current`] with the given [`Key`], if it exists. Otherwise, this is equivalent to
+/// [`Handle::spawn`].
+pub fn spawn_on<T>(
+    handle: &Handle,
+    key: impl Key,
+    root_span: impl Into<Span>,
+    future: T,
+) -> JoinHandle<T::Output>
 where
     T: Future + Send + 'static,
     T::Output: Send + 'static,
 {
     if let Some(registry) = Registry::try_current() {
-        tokio::spawn(registry.register(key, root_span).instrument(future))
+        registry.spawn_on(handle, key, root_span, future)
     } else {
-        tokio::spawn(future)
+        handle.spawn(future)
     }
 }
 
@@ -58,3 +84,88 @@ where
         tokio::spawn(future)
     }
 }
+
+/// Spawns a new `!Send` asynchronous task on the current [`LocalSet`](tokio::task::LocalSet),
+/// instrumented with the given root [`Span`], returning a [`JoinHandle`] for it.
+///
+/// The spawned task will be registered in the current [`Registry`](crate::Registry) returned by
+/// [`Registry::try_current`] with the given [`Key`], if it exists. Otherwise, this is equivalent to
+/// [`tokio::task::spawn_local`].
+pub fn spawn_local<T>(key: impl Key, root_span: impl Into<Span>, future: T) -> JoinHandle<T::Output>
+where
+    T: Future + 'static,
+    T::Output: 'static,
+{
+    if let Some(registry) = Registry::try_current() {
+        registry.spawn_local(key, root_span, future)
+    } else {
+        tokio::task::spawn_local(future)
+    }
+}
+
+/// Spawns a new asynchronous task instrumented with the root [`Span`] derived from the given key
+/// with [`ToRootSpan`], returning a [`JoinHandle`] for it.
+///
+/// This is a shorthand for [`spawn`] with `key.to_root_span()` as the root span.
+pub fn spawn_derived_root<T>(key: impl Key + ToRootSpan, future: T) -> JoinHandle<T::Output>
+where
+    T: Future + Send + 'static,
+    T::Output: Send + 'static,
+{
+    let root_span = key.to_root_span();
+    spawn(key, root_span, future)
+}
+
+impl Registry {
+    /// Spawns a new asynchronous task on the runtime of the given [`Handle`], registered in this
+    /// registry with the given key and instrumented with the given root [`Span`], returning a
+    /// [`JoinHandle`] for it.
+    ///
+    /// This is useful for placing instrumented tasks onto an explicit runtime, e.g., when IO and
+    /// compute work are separated into different runtimes.
+    pub fn spawn_on<T>(
+        &self,
+        handle: &Handle,
+        key: impl Key,
+        root_span: impl Into<Span>,
+        future: T,
+    ) -> JoinHandle<T::Output>
+    where
+        T: Future + Send + 'static,
+        T::Output: Send + 'static,
+    {
+        handle.spawn(self.register(key, root_span).instrument(future))
+    }
+
+    /// Spawns a new `!Send` asynchronous task on the current
+    /// [`LocalSet`](tokio::task::LocalSet), registered in this registry with the given key and
+    /// instrumented with the given root [`Span`], returning a [`JoinHandle`] for it.
+    pub fn spawn_local<T>(
+        &self,
+        key: impl Key,
+        root_span: impl Into<Span>,
+        future: T,
+    ) -> JoinHandle<T::Output>
+    where
+        T: Future + 'static,
+        T::Output: 'static,
+    {
+        tokio::task::spawn_local(self.register(key, root_span).instrument(future))
+    }
+
+    /// Spawns a new asynchronous task registered in this registry with the given key, and
+    /// instrumented with the root [`Span`] derived from the key with [`ToRootSpan`], returning a
+    /// [`JoinHandle`] for it.
+    pub fn spawn_derived_root<T>(
+        &self,
+        key: impl Key + ToRootSpan,
+        future: T,
+    ) -> JoinHandle<T::Output>
+    where
+        T: Future + Send + 'static,
+        T::Output: Send + 'static,
+    {
+        let root_span = key.to_root_span();
+        self.spawn_on(&Handle::current(), key, root_span, future)
+    }
+}
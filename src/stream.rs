@@ -0,0 +1,136 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::{Stream, TryStream};
+use pin_project::{pin_project, pinned_drop};
+
+use crate::io::State;
+use crate::Span;
+
+/// The stream for [`InstrumentStream`] and [`InstrumentTryStream`].
+///
+/// The const parameter `TRY` is whether the stream is a [`TryStream`], whose span is marked as
+/// failed once it yields an error.
+#[pin_project(PinnedDrop)]
+pub struct InstrumentedStream<S, const TRY: bool = false> {
+    #[pin]
+    inner: S,
+    state: State,
+}
+
+/// The stream for [`InstrumentTryStream`].
+pub type InstrumentedTryStream<S> = InstrumentedStream<S, true>;
+
+impl<S, const TRY: bool> InstrumentedStream<S, TRY> {
+    fn new(inner: S, span: impl Into<Span>) -> Self {
+        Self {
+            inner,
+            state: State::new(span),
+        }
+    }
+
+    /// Returns a reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Poll the inner stream with `poll_next` in the span, marking the span as failed if `failed`
+    /// returns true for the yielded item, and popping the span once the stream is exhausted.
+    fn poll_next_inner<T>(
+        self: Pin<&mut Self>,
+        poll_next: impl FnOnce(Pin<&mut S>) -> Poll<Option<T>>,
+        failed: impl FnOnce(&T) -> bool,
+    ) -> Poll<Option<T>> {
+        let this = self.project();
+        let poll = this.state.enter("stream", || poll_next(this.inner));
+        match &poll {
+            Poll::Ready(Some(item)) if failed(item) => this.state.mark_failed(),
+            Poll::Ready(None) => {
+                // The stream is exhausted, pop the span as an instrumented future does when ready.
+                this.state.remove("stream");
+                *this.state = State::Disabled;
+            }
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl<S: Stream> Stream for InstrumentedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_next_inner(|inner| inner.poll_next(cx), |_| false)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S: TryStream> Stream for InstrumentedTryStream<S> {
+    type Item = Result<S::Ok, S::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_next_inner(|inner| inner.try_poll_next(cx), Result::is_err)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[pinned_drop]
+impl<S, const TRY: bool> PinnedDrop for InstrumentedStream<S, TRY> {
+    fn drop(self: Pin<&mut Self>) {
+        self.project().state.remove("stream");
+    }
+}
+
+/// Attach spans to a [`Stream`] to be traced in the await-tree.
+///
+/// [`Instrumented`](crate::Instrumented) follows the polling model of a future: the span is pushed
+/// on the first poll and popped once the future is ready. A stream can be ready for multiple times
+/// instead, so the span is stepped into on every [`poll_next`](Stream::poll_next) and persists
+/// across the yielded items, shown as not current while the consumer is processing an item. It's
+/// popped once the stream is exhausted, i.e., yields `None`, or dropped.
+pub trait InstrumentStream: Stream + Sized {
+    /// Instrument the stream with a span.
+    fn instrument_await_stream(self, span: impl Into<Span>) -> InstrumentedStream<Self> {
+        InstrumentedStream::new(self, span)
+    }
+}
+impl<T: Stream> InstrumentStream for T {}
+
+/// Attach spans to a [`TryStream`] to be traced in the await-tree.
+///
+/// The span follows the same polling model as [`InstrumentStream`]: it's stepped into on every
+/// poll of the stream and persists across the yielded items, until the stream is exhausted or
+/// dropped. Once the stream yields an error, the span is marked as failed, shown like
+/// `name (failed)`, to tell an error termination apart from a clean completion in the output.
+pub trait InstrumentTryStream: TryStream + Sized {
+    /// Instrument the fallible stream with a span.
+    fn instrument_try_stream(self, span: impl Into<Span>) -> InstrumentedTryStream<Self> {
+        InstrumentedStream::new(self, span)
+    }
+}
+impl<T: TryStream> InstrumentTryStream for T {}
@@ -0,0 +1,222 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures_core::Stream;
+use parking_lot::Mutex;
+use tokio::time::{Interval, MissedTickBehavior};
+
+use crate::context::TreeContext;
+use crate::{AnyKey, Key, Registry, Span, Tree};
+
+/// An event of the mutation of an await-tree, emitted to the streams returned by
+/// [`Registry::subscribe`].
+#[derive(Debug, Clone)]
+pub enum TreeEvent {
+    /// A span is opened, when the instrumented future is first polled.
+    SpanOpened {
+        /// The key of the await-tree.
+        key: AnyKey,
+        /// The id of the span node, unique among the live spans of the await-tree.
+        node_id: usize,
+        /// The span.
+        span: Span,
+    },
+    /// A span is closed, when the instrumented future is ready or dropped.
+    SpanClosed {
+        /// The key of the await-tree.
+        key: AnyKey,
+        /// The id of the span node, the same as the one in [`TreeEvent::SpanOpened`].
+        node_id: usize,
+        /// The elapsed time of the span.
+        elapsed: Duration,
+    },
+}
+
+#[derive(Default)]
+struct ChannelState {
+    events: VecDeque<TreeEvent>,
+    lagged: u64,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// A bounded channel from the registry to a subscriber.
+#[derive(Default)]
+struct Channel {
+    state: Mutex<ChannelState>,
+}
+
+/// The subscribers of the events of a registry.
+pub(crate) struct Subscribers {
+    capacity: usize,
+    channels: Mutex<Vec<Arc<Channel>>>,
+    /// Whether there's any subscriber, used as a fast path for emitting events.
+    active: AtomicBool,
+}
+
+impl Subscribers {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            channels: Default::default(),
+            active: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether there's any subscriber.
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn subscribe(&self) -> TreeEventStream {
+        let channel = Arc::new(Channel::default());
+        self.channels.lock().push(channel.clone());
+        self.active.store(true, Ordering::Relaxed);
+        TreeEventStream { channel }
+    }
+
+    /// Emit the event to all subscribers. The oldest event is dropped if a subscriber falls
+    /// behind by more than `capacity` events.
+    pub(crate) fn emit(&self, event: TreeEvent) {
+        let mut channels = self.channels.lock();
+        // Remove the channels whose streams are dropped.
+        channels.retain(|c| Arc::strong_count(c) > 1);
+        self.active.store(!channels.is_empty(), Ordering::Relaxed);
+
+        for channel in channels.iter() {
+            let mut state = channel.state.lock();
+            if state.events.len() >= self.capacity {
+                state.events.pop_front();
+                state.lagged += 1;
+            }
+            state.events.push_back(event.clone());
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Drop for Subscribers {
+    fn drop(&mut self) {
+        for channel in self.channels.get_mut().iter() {
+            let mut state = channel.state.lock();
+            state.closed = true;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The stream of events of the await-trees in a registry, returned by [`Registry::subscribe`].
+///
+/// The stream ends once the registry and all of its await-trees are dropped.
+pub struct TreeEventStream {
+    channel: Arc<Channel>,
+}
+
+impl std::fmt::Debug for TreeEventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TreeEventStream")
+            .field("lagged", &self.lagged())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TreeEventStream {
+    /// Returns the number of events dropped so far because this stream fell behind.
+    pub fn lagged(&self) -> u64 {
+        self.channel.state.lock().lagged
+    }
+}
+
+impl Stream for TreeEventStream {
+    type Item = TreeEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.channel.state.lock();
+        if let Some(event) = state.events.pop_front() {
+            Poll::Ready(Some(event))
+        } else if state.closed {
+            Poll::Ready(None)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// The stream of the snapshots of a single await-tree, returned by [`Registry::watch_key`].
+pub struct TreeWatchStream {
+    context: Weak<TreeContext>,
+    interval: Interval,
+}
+
+impl std::fmt::Debug for TreeWatchStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TreeWatchStream")
+            .field("period", &self.interval.period())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Stream for TreeWatchStream {
+    type Item = Tree;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.context.strong_count() == 0 {
+            return Poll::Ready(None);
+        }
+        let _ = std::task::ready!(self.interval.poll_tick(cx));
+        Poll::Ready(self.context.upgrade().map(|c| c.tree().clone()))
+    }
+}
+
+impl Registry {
+    /// Watch the await-tree with given key, returning a stream that yields a snapshot of the tree
+    /// immediately and then on every `interval`.
+    ///
+    /// The stream ends when the tree root is dropped, typically when the task finishes, or
+    /// immediately if the key does not exist. Note that the tree is cloned on every tick. Must be
+    /// called within a tokio runtime with the time driver enabled.
+    pub fn watch_key(&self, key: impl Key, interval: Duration) -> TreeWatchStream {
+        let context = self
+            .context(key)
+            .map(|c| Arc::downgrade(&c))
+            .unwrap_or_default();
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        TreeWatchStream { context, interval }
+    }
+
+    /// Subscribe to the events of the await-trees in this registry, which are emitted when spans
+    /// are opened or closed.
+    ///
+    /// This enables live visualization without polling the snapshots. Only the events after the
+    /// subscription are received. If the subscriber falls behind by more than
+    /// [`ConfigBuilder::subscriber_capacity`](crate::ConfigBuilder::subscriber_capacity) events,
+    /// the oldest ones are dropped and counted by [`TreeEventStream::lagged`].
+    pub fn subscribe(&self) -> TreeEventStream {
+        self.subscribers().subscribe()
+    }
+}
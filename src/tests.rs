@@ -12,5 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// The `disabled` feature strips the spans, so the tests checking the recorded spans only run
+// without it.
+
+#[cfg(not(feature = "disabled"))]
+mod block_on;
+#[cfg(feature = "disabled")]
+mod disabled;
+#[cfg(not(feature = "disabled"))]
 mod functionality;
+#[cfg(not(feature = "disabled"))]
+mod io;
+mod join_set;
+#[cfg(feature = "serde")]
+mod serde;
 mod spawn;
+#[cfg(not(feature = "disabled"))]
+mod stream;
+#[cfg(not(feature = "disabled"))]
+mod subscribe;
+#[cfg(all(feature = "tracing", not(feature = "disabled")))]
+mod tracing;
@@ -0,0 +1,44 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use futures::channel::oneshot;
+
+use crate::{current_tree, Config, InstrumentAwait, Registry};
+
+#[test]
+fn test_block_on() {
+    let registry = Registry::new(Config::default());
+    let (tx, rx) = oneshot::channel();
+
+    let waker = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        tx.send(()).unwrap();
+    });
+    let tree = registry.block_on("cli", "main", async {
+        rx.instrument_await("recv").await.unwrap();
+        async { current_tree().unwrap() }
+            .instrument_await("work")
+            .await
+    });
+    waker.join().unwrap();
+
+    assert_eq!(tree.current_path(), ["main".into(), "work".into()]);
+    // The tree is unregistered after completion.
+    assert!(registry.get("cli").is_none());
+
+    // Run as is without a registry.
+    assert_eq!(crate::block_on("cli", "main", async { 42 }), 42);
+}
@@ -0,0 +1,52 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{current_tree, Config, InstrumentAwait, Registry, Span};
+
+#[tokio::test]
+async fn test_disabled() {
+    let registry = Registry::new(Config::default());
+
+    let path = registry
+        .register((), "root")
+        .instrument(async {
+            async { current_tree().unwrap().current_path() }
+                .instrument_await("stripped")
+                .await
+        })
+        .await;
+    assert_eq!(path, ["root".into()]);
+}
+
+#[tokio::test]
+async fn test_disabled_no_conversion() {
+    struct Unconvertible;
+    impl From<Unconvertible> for Span {
+        fn from(_: Unconvertible) -> Self {
+            panic!("span converted while the instrumentation is stripped")
+        }
+    }
+
+    let registry = Registry::new(Config::default());
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            async {}.instrument_await(Unconvertible).await;
+            async {}.instrument_await_if(true, Unconvertible).await;
+            async {}.instrument_await_eager(Unconvertible).await;
+            async {}.instrument_await_boxed(Unconvertible).await;
+        })
+        .await;
+}
@@ -12,12 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use futures::future::{join_all, poll_fn, select_all};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::{join3, join_all, pending, poll_fn, ready, select, select_all, Either};
 use futures::{pin_mut, FutureExt, Stream, StreamExt};
 use itertools::Itertools;
 
 use crate::root::current_context;
-use crate::{Config, InstrumentAwait, Registry};
+use crate::{
+    current_span_handle, current_task_id, current_tree, enter_span, rename_current_span,
+    set_current_progress, with_parent_span, Config, ConfigBuilder, DetachReason, InstrumentAwait,
+    Registry, Span, SpanExt, Tree, TreeStyle,
+};
 
 async fn sleep(time: u64) {
     tokio::time::sleep(std::time::Duration::from_millis(time)).await;
@@ -190,3 +200,1209 @@ async fn test_await_tree() {
 
     assert_eq!(actual_counts, expected_counts);
 }
+
+#[tokio::test]
+async fn test_lazy_span() {
+    for verbose in [false, true] {
+        let config = ConfigBuilder::default().verbose(verbose).build().unwrap();
+        let registry = Registry::new(config);
+        let built = AtomicUsize::new(0);
+        let span_fn = |name: &'static str| {
+            || {
+                built.fetch_add(1, Ordering::Relaxed);
+                name.into()
+            }
+        };
+
+        // Not in a context, never built.
+        async {}.instrument_await_with(span_fn("unused")).await;
+        assert_eq!(built.load(Ordering::Relaxed), 0);
+
+        let tree = registry
+            .register((), "root")
+            .instrument(async {
+                async { current_tree().unwrap().to_string() }
+                    .verbose_instrument_await_with(span_fn("lazy verbose"))
+                    .instrument_await_with(span_fn("lazy"))
+                    .await
+            })
+            .await;
+
+        assert!(tree.contains("lazy"));
+        assert_eq!(tree.contains("lazy verbose"), verbose);
+        assert_eq!(built.load(Ordering::Relaxed), 1 + verbose as usize);
+    }
+}
+
+#[tokio::test]
+async fn test_max_depth() {
+    fn nested(n: usize) -> futures::future::BoxFuture<'static, usize> {
+        async move {
+            if n == 0 {
+                current_context().unwrap().tree().active_node_count()
+            } else {
+                nested(n - 1).instrument_await(format!("nested {n}")).await
+            }
+        }
+        .boxed()
+    }
+
+    let config = ConfigBuilder::default().max_depth(10).build().unwrap();
+    let registry = Registry::new(config);
+
+    for (n, expected) in [(5, 6), (9, 10), (10, 10), (100, 10)] {
+        let count = registry.register((), "root").instrument(nested(n)).await;
+        assert_eq!(count, expected);
+    }
+}
+
+#[tokio::test]
+async fn test_node_recycling() {
+    let registry = Registry::new(Config::default());
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            for i in 0..1000 {
+                // Detach a child span from its parent, then drop it.
+                let mut child = Box::pin(pending::<()>().instrument_await("child"));
+                async {
+                    poll_fn(|cx| Poll::Ready(child.as_mut().poll(cx).is_pending())).await;
+                }
+                .instrument_await("parent")
+                .await;
+                drop(child);
+
+                async {
+                    // The nodes of the removed spans are recycled by the arena, without stale
+                    // children.
+                    let tree = current_tree().unwrap();
+                    assert_eq!(tree.current_path(), ["root".into(), i.to_string().into()]);
+                    assert_eq!(tree.active_node_count(), 2);
+                    assert!(tree.allocated_node_count() <= 3);
+                }
+                .instrument_await(i.to_string())
+                .await;
+            }
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn test_max_detached() {
+    async fn detach_many() -> Vec<impl Future<Output = ()> + Unpin> {
+        let mut detached = Vec::new();
+        for i in 0..10 {
+            // The pending future is polled under `select` then detached when `select` is closed,
+            // and never remounted.
+            let mut fut = pending::<()>().instrument_await(format!("fut {i}"));
+            select(&mut fut, ready(())).instrument_await("select").await;
+            detached.push(fut);
+            // Make the start time of each span distinct.
+            sleep(10).await;
+        }
+        detached
+    }
+
+    let registry = Registry::new(Config::default());
+    registry
+        .register((), "root")
+        .instrument(async {
+            let _detached = detach_many().await;
+            let tree = current_tree().unwrap();
+            assert_eq!(tree.detached_node_count(), 10);
+        })
+        .await;
+
+    let registry = Registry::new(ConfigBuilder::default().max_detached(3).build().unwrap());
+    registry
+        .register((), "root")
+        .instrument(async {
+            let mut detached = detach_many().await;
+            let tree = current_tree().unwrap();
+            assert_eq!(tree.detached_node_count(), 3);
+            let tree = tree.to_string();
+            assert!(!tree.contains("fut 6"), "{tree}");
+            assert!(tree.contains("fut 7"), "{tree}");
+
+            // Polling an evicted future does not record its span.
+            poll_fn(|cx| {
+                let _ = detached[0].poll_unpin(cx);
+                Poll::Ready(())
+            })
+            .await;
+            let tree = current_tree().unwrap();
+            assert_eq!(tree.active_node_count(), 4);
+            assert_eq!(tree.detached_node_count(), 3);
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn test_detach_reason() {
+    let registry = Registry::new(Config::default());
+
+    let tree = registry
+        .register((), "root")
+        .instrument(async {
+            // Detached when `select` is ready.
+            let mut select_arm = pending::<()>().instrument_await("select arm");
+            select(&mut select_arm, ready(()))
+                .instrument_await("select")
+                .await;
+
+            // Detached when the parent is dropped while pending.
+            let mut aborted = pending::<()>().instrument_await("aborted");
+            let parent = async { (&mut aborted).await }.instrument_await("parent");
+            assert!(parent.now_or_never().is_none());
+
+            // Detached when `select` is ready while being remounted.
+            let mut remounted = pending::<()>().instrument_await("remounted");
+            let handle = current_span_handle().unwrap();
+            with_parent_span(
+                handle,
+                select(&mut remounted, ready(())).instrument_await("select"),
+            )
+            .await;
+
+            let tree = current_tree().unwrap();
+            let reasons = tree
+                .nodes()
+                .filter_map(|n| Some((n.span().name(), n.detach_reason()?)))
+                .collect_vec();
+            assert_eq!(
+                reasons,
+                [
+                    ("select arm", DetachReason::SelectArm),
+                    ("aborted", DetachReason::Aborted),
+                    ("remounted", DetachReason::Remount),
+                ]
+            );
+
+            // Reattached spans are no longer marked.
+            poll_fn(|cx| {
+                let _ = select_arm.poll_unpin(cx);
+                Poll::Ready(())
+            })
+            .await;
+            current_tree().unwrap()
+        })
+        .await;
+
+    let tree = tree.to_string();
+    assert!(!tree.contains(": select-arm]"), "{tree}");
+    assert!(tree.contains(": aborted]\n"), "{tree}");
+    assert!(tree.contains(": remount]\n"), "{tree}");
+}
+
+#[tokio::test]
+async fn test_snapshot_elapsed_now() {
+    let registry = Registry::new(Config::default());
+    let _root = registry.register((), "root");
+
+    let tree = registry.get(()).unwrap();
+    assert!(!tree.is_frozen());
+
+    let snapshot = tree.snapshot_elapsed_now();
+    assert!(snapshot.is_frozen());
+    let display = snapshot.to_string();
+    sleep(50).await;
+    assert_eq!(snapshot.to_string(), display);
+    assert_eq!(snapshot.snapshot_elapsed_now().to_string(), display);
+    assert_ne!(tree.to_string(), display);
+}
+
+#[tokio::test]
+async fn test_sample_rate() {
+    let config = ConfigBuilder::default()
+        .sample_rate(0.0)
+        .warn_threshold(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+
+    let (short, long) = registry
+        .register((), "root")
+        .instrument(async {
+            let short = async { current_tree().unwrap() }
+                .instrument_await("short")
+                .await;
+            // Recorded once it has been running for longer than the warning threshold.
+            let long = async {
+                sleep(100).await;
+                current_tree().unwrap()
+            }
+            .instrument_await("long")
+            .await;
+            (short, long)
+        })
+        .await;
+    assert_eq!(short.current_path(), ["root".into()]);
+    assert_eq!(long.current_path(), ["root".into(), "long".into()]);
+
+    let config = ConfigBuilder::default().sample_rate(0.5).build().unwrap();
+    let registry = Registry::new(config);
+    let depths = registry
+        .register((), "root")
+        .instrument(join_all((0..1000).map(|_| {
+            async { current_tree().unwrap().current_path().len() }.instrument_await("fut")
+        })))
+        .await;
+    let sampled = depths.iter().filter(|&&d| d == 2).count();
+    assert!((300..700).contains(&sampled), "{sampled}");
+}
+
+#[tokio::test]
+async fn test_remount_keeps_start_time() {
+    let registry = Registry::new(Config::default());
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            let mut polled = false;
+            let mut fut = poll_fn(|cx| {
+                if polled {
+                    Poll::Ready(current_tree().unwrap())
+                } else {
+                    polled = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            })
+            .instrument_await("fut");
+            let fut_node = |tree: &Tree| {
+                tree.nodes()
+                    .find(|n| n.span().name() == "fut")
+                    .map(|n| (n.elapsed(), n.start_time(), n.is_detached()))
+            };
+
+            select(&mut fut, ready(())).instrument_await("select").await;
+            let (detached_elapsed, start_time, is_detached) =
+                fut_node(&current_tree().unwrap()).unwrap();
+            assert!(is_detached);
+
+            sleep(100).await;
+            // Remount `fut` under the root span.
+            let tree = (&mut fut).await;
+            let (elapsed, remounted_start_time, is_detached) = fut_node(&tree).unwrap();
+            assert!(!is_detached);
+            assert!(
+                elapsed >= detached_elapsed + Duration::from_millis(90),
+                "{elapsed:?}"
+            );
+            let drift = remounted_start_time
+                .duration_since(start_time)
+                .unwrap_or_else(|e| e.duration());
+            assert!(drift < Duration::from_millis(50), "{drift:?}");
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn test_instrument_await_eager() {
+    let config = ConfigBuilder::default()
+        .record_unpolled(true)
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            let mut starved = pending::<()>().instrument_await_eager("starved");
+            let never = ready(()).instrument_await_eager("never");
+            let tree = current_tree().unwrap();
+            let polled = tree
+                .nodes()
+                .map(|n| (n.span().name(), n.is_polled()))
+                .sorted()
+                .collect_vec();
+            assert_eq!(
+                polled,
+                [("never", false), ("root", true), ("starved", false)]
+            );
+            let tree = tree.to_string();
+            assert!(tree.contains("  starved (not yet polled) ["), "{tree}");
+
+            // Removed if dropped without being polled.
+            drop(never);
+            // Stepped in when first polled.
+            poll_fn(|cx| {
+                let _ = starved.poll_unpin(cx);
+                Poll::Ready(())
+            })
+            .await;
+            let tree = current_tree().unwrap().to_string();
+            let lines = tree.lines().collect_vec();
+            assert_eq!(lines.len(), 2, "{tree}");
+            assert!(lines[1].starts_with("  starved ["), "{tree}");
+        })
+        .await;
+
+    // Not recorded until polled without the configuration.
+    let registry = Registry::new(Config::default());
+    let tree = registry
+        .register((), "root")
+        .instrument(async {
+            let _starved = pending::<()>().instrument_await_eager("starved");
+            current_tree().unwrap()
+        })
+        .await;
+    assert_eq!(tree.to_string().lines().count(), 1);
+}
+
+#[tokio::test]
+async fn test_find() {
+    let registry = Registry::new(Config::default());
+
+    let found = registry
+        .register((), "root")
+        .instrument(async {
+            async {
+                sleep(100).await;
+                current_tree()
+                    .unwrap()
+                    .find(|s| s.to_string().starts_with("flush"))
+            }
+            .instrument_await("flush 1")
+            .instrument_await("middle")
+            .await
+        })
+        .await;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].0.to_string(), "flush 1");
+    assert!(found[0].1 >= std::time::Duration::from_millis(50));
+}
+
+#[tokio::test]
+async fn test_stalled() {
+    let registry = Registry::new(Config::default());
+
+    let tree = registry
+        .register((), "root")
+        .instrument(async {
+            let check = async {
+                sleep(100).await;
+                current_tree().unwrap()
+            };
+            let (_, tree) = futures::future::join(
+                sleep(200).instrument_await("slow"),
+                check.instrument_await("check"),
+            )
+            .await;
+            tree
+        })
+        .await;
+
+    assert!(tree.is_stalled(Duration::from_millis(50)));
+    assert!(!tree.is_stalled(Duration::from_secs(10)));
+
+    let stalled = tree.stalled_spans(Duration::from_millis(50));
+    let names = stalled.iter().map(|(span, _)| span.to_string()).sorted();
+    assert_eq!(names.collect_vec(), ["check", "slow"]);
+    assert!(tree.stalled_spans(Duration::from_secs(10)).is_empty());
+}
+
+#[tokio::test]
+async fn test_prune() {
+    let registry = Registry::new(Config::default());
+
+    let mut tree = registry
+        .register((), "root")
+        .instrument(async {
+            let fast = async {
+                async {
+                    sleep(50).await;
+                    current_tree().unwrap()
+                }
+                .instrument_await("fast leaf")
+                .await
+            };
+            let (_, tree) = futures::future::join(
+                async { sleep(200).instrument_await("slow leaf").await }
+                    .instrument_await("fast parent"),
+                fast.instrument_await("fast"),
+            )
+            .await;
+            tree
+        })
+        .await;
+    // Freeze the tree so that the output of the pruned tree is comparable.
+    tree.freeze();
+
+    let pruned = tree.prune(|span, _| span.to_string().starts_with("slow"));
+    let spans = pruned.spans().map(|(span, _, _)| span.to_string()).sorted();
+    assert_eq!(spans.collect_vec(), ["fast parent", "root", "slow leaf"]);
+    assert_eq!(pruned.current_path(), ["root".into()]);
+
+    let pruned = tree.prune(|_, _| false);
+    assert_eq!(pruned.to_string().lines().count(), 1);
+    assert_eq!(pruned.current_path(), ["root".into()]);
+
+    let pruned = tree.prune(|_, _| true);
+    assert_eq!(pruned.to_string(), tree.to_string());
+    assert_eq!(pruned.current_path(), tree.current_path());
+}
+
+#[test]
+fn test_into_instrumented() {
+    let registry = Registry::new(Config::default());
+    let future = registry.register((), "root").into_instrumented(async {
+        let path = || current_tree().unwrap().current_path();
+        let first = path();
+        let mut polled = false;
+        poll_fn(|_| {
+            if std::mem::replace(&mut polled, true) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .instrument_await("pending")
+        .await;
+        (first, path())
+    });
+    pin_mut!(future);
+
+    // Poll manually outside of any tokio runtime.
+    let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
+    assert!(future.as_mut().poll(&mut cx).is_pending());
+    assert!(current_tree().is_none());
+    let tree = registry.get(()).unwrap().to_string();
+    assert!(tree.contains("pending"), "{tree}");
+
+    let Poll::Ready((first, second)) = future.as_mut().poll(&mut cx) else {
+        panic!("future should be ready");
+    };
+    assert_eq!(first, ["root".into()]);
+    assert_eq!(second, ["root".into()]);
+}
+
+#[tokio::test]
+async fn test_instrument_await_since() {
+    let registry = Registry::new(Config::default());
+    let start_time = std::time::Instant::now() - std::time::Duration::from_secs(60);
+
+    let tree = registry
+        .register((), "root")
+        .instrument(async {
+            async { current_tree().unwrap() }
+                .instrument_await_since("resumed", start_time)
+                .await
+        })
+        .await;
+    let found = tree.find(|s| s.to_string() == "resumed");
+    assert_eq!(found.len(), 1);
+    assert!(found[0].1 >= std::time::Duration::from_secs(59));
+}
+
+#[tokio::test]
+async fn test_category() {
+    for show_category in [false, true] {
+        let config = ConfigBuilder::default()
+            .show_category(show_category)
+            .build()
+            .unwrap();
+        let registry = Registry::new(config);
+
+        registry
+            .register((), "root")
+            .instrument(async {
+                let check = async {
+                    let totals = registry.total_elapsed_by_category();
+                    assert_eq!(totals.keys().sorted().collect_vec(), [&"compute", &"io"]);
+
+                    let tree = registry.get(()).unwrap().to_string();
+                    assert_eq!(tree.contains("read (io)"), show_category, "{tree}");
+                };
+                join_all([
+                    sleep(100).instrument_boxed("read".category("io")),
+                    sleep(100).instrument_boxed(format!("compute {}", 1).category("compute")),
+                    sleep(100).instrument_boxed("uncategorized"),
+                    async {
+                        sleep(50).await;
+                        check.await
+                    }
+                    .instrument_boxed("check"),
+                ])
+                .await;
+            })
+            .await;
+    }
+}
+
+#[tokio::test]
+async fn test_span_order() {
+    let registry = Registry::new(Config::default());
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            let check = async {
+                let tree = registry.get(()).unwrap().to_string();
+                let position = |name| tree.find(name).unwrap_or_else(|| panic!("{tree}"));
+                // Spans with an order come after the unordered ones, then sorted by the order,
+                // regardless of the start time.
+                assert!(position("check") < position("read"), "{tree}");
+                assert!(position("read") < position("write"), "{tree}");
+            };
+            select_all([
+                pending().instrument_boxed("write".order(2)),
+                pending().instrument_boxed("read".order(1)),
+                check.instrument_boxed("check"),
+            ])
+            .await;
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn test_tree_style() {
+    for (style, expected) in [
+        (TreeStyle::Spaces, ["root", "  a", "    c", "  b"]),
+        (TreeStyle::Unicode, ["root", "├─ a", "│  └─ c", "└─ b"]),
+        (TreeStyle::Ascii, ["root", "|- a", "|  `- c", "`- b"]),
+    ] {
+        let config = ConfigBuilder::default().tree_style(style).build().unwrap();
+        let registry = Registry::new(config);
+
+        let tree = registry
+            .register((), "root")
+            .instrument(async {
+                let check = async { current_tree().unwrap() }.instrument_await("c");
+                select_all([
+                    pending().instrument_boxed("b".order(2)),
+                    check.instrument_boxed("a".order(1)),
+                ])
+                .await
+                .0
+            })
+            .await;
+        let tree = tree.to_string();
+        let lines = tree
+            .lines()
+            .map(|l| l.split(" [").next().unwrap())
+            .collect_vec();
+        assert_eq!(lines, expected, "{tree}");
+    }
+}
+
+#[tokio::test]
+async fn test_max_span_name_len() {
+    let config = ConfigBuilder::default()
+        .max_span_name_len(4)
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+
+    let tree = registry
+        .register((), "root")
+        .instrument(async {
+            async { current_tree().unwrap() }
+                .instrument_await("数据库查询")
+                .instrument_await("数据库查")
+                .await
+        })
+        .await;
+    let tree = tree.to_string();
+    let lines = tree.lines().collect_vec();
+    assert!(lines[1].starts_with("  数据库查 ["), "{tree}");
+    assert!(lines[2].starts_with("    数据库查… ["), "{tree}");
+}
+
+#[tokio::test]
+async fn test_root_and_current_span() {
+    let registry = Registry::new(Config::default());
+    let root = registry.register((), "root");
+    let tree = registry.get(()).unwrap();
+    assert_eq!(tree.root_span(), "root".into());
+    assert_eq!(tree.current_span(), "root".into());
+
+    let tree = root
+        .instrument(
+            async { current_tree().unwrap() }
+                .instrument_await("inner")
+                .instrument_await("outer"),
+        )
+        .await;
+    assert_eq!(tree.root_span(), "root".into());
+    assert_eq!(tree.current_span(), "inner".into());
+}
+
+#[tokio::test]
+async fn test_enter_span() {
+    let path = || current_tree().unwrap().current_path();
+
+    // No-op if not instrumented.
+    drop(enter_span("nothing"));
+
+    let registry = Registry::new(Config::default());
+    registry
+        .register((), "root")
+        .instrument(async {
+            {
+                let _outer = enter_span("outer");
+                let _inner = enter_span("inner");
+                assert_eq!(path(), ["root".into(), "outer".into(), "inner".into()]);
+            }
+            assert_eq!(path(), ["root".into()]);
+
+            // Guards dropped out of order close the spans entered later as well.
+            let outer = enter_span("outer");
+            let inner = enter_span("inner");
+            drop(outer);
+            assert_eq!(path(), ["root".into()]);
+            drop(inner);
+            assert_eq!(path(), ["root".into()]);
+
+            poll_fn(|_| {
+                let _guard = enter_span("sync");
+                assert_eq!(path(), ["root".into(), "poll".into(), "sync".into()]);
+                Poll::Ready(())
+            })
+            .instrument_await("poll")
+            .await;
+            assert_eq!(current_tree().unwrap().active_node_count(), 1);
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn test_either() {
+    let registry = Registry::new(Config::default());
+
+    let tree = registry
+        .register((), "root")
+        .instrument(async {
+            let mut read =
+                Either::Left(pending().instrument_await("read cache")).instrument_await("read");
+            // Poll the left branch once.
+            assert!(poll_fn(|cx| Poll::Ready(read.poll_unpin(cx)))
+                .await
+                .is_pending());
+
+            // Switch to the right branch, which drops the left one.
+            read = Either::Right(
+                poll_fn(|_| Poll::Ready(current_tree().unwrap())).instrument_await("read disk"),
+            )
+            .instrument_await("read");
+            read.await
+        })
+        .await;
+
+    assert_eq!(
+        tree.current_path(),
+        ["root".into(), "read".into(), "read disk".into()]
+    );
+    // The span of the left branch is removed, without any detached span left.
+    assert_eq!(tree.active_node_count(), 3, "{tree}");
+}
+
+#[tokio::test]
+async fn test_instrument_await_boxed() {
+    /// Recursively nest the futures, each of which holds a large buffer across the await point.
+    async fn deep(depth: usize) -> usize {
+        let buf = [depth as u8; 4096];
+        let inner = if depth == 0 {
+            current_tree().unwrap().current_path().len()
+        } else {
+            deep(depth - 1).instrument_await_boxed("deep").await
+        };
+        inner + usize::from(buf[depth % buf.len()] == depth as u8) - 1
+    }
+
+    // Each level is boxed, so the future to be moved around stays small, instead of growing with
+    // the depth.
+    let registry = Registry::new(Config::default());
+    let depth = registry.register((), "root").instrument(deep(100)).await;
+    assert_eq!(depth, 101);
+    assert!(std::mem::size_of_val(&deep(100)) < 8192);
+    assert!(std::mem::size_of_val(&deep(0).instrument_await_boxed("deep")) < 1024);
+}
+
+#[tokio::test]
+async fn test_warn_after() {
+    let registry = Registry::new(Config::default());
+
+    let tree = registry
+        .register((), "root")
+        .instrument(
+            async {
+                sleep(30).await;
+                current_tree().unwrap()
+            }
+            .instrument_await("fast".warn_after(Duration::from_millis(10)))
+            .instrument_await("slow"),
+        )
+        .await;
+    let tree = tree.to_string();
+    let lines = tree.lines().collect_vec();
+    assert!(
+        lines[1].starts_with("  slow [") && !lines[1].contains("!!!"),
+        "{tree}"
+    );
+    assert!(lines[2].starts_with("    fast [!!! "), "{tree}");
+}
+
+#[tokio::test]
+async fn test_collapse_siblings() {
+    let config = ConfigBuilder::default()
+        .collapse_siblings(true)
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+
+    let check = async { current_tree().unwrap() }.instrument_boxed("check".order(2));
+    let mut arms = (0..3)
+        .map(|_| pending().instrument_boxed("fetch".order(1)))
+        .collect_vec();
+    arms.push(pending().instrument_boxed("fetch".order(3)));
+    arms.push(check);
+
+    let (tree, _, _) = registry
+        .register((), "root")
+        .instrument(select_all(arms))
+        .await;
+    let tree = tree.to_string();
+    let lines = tree.lines().collect_vec();
+    assert_eq!(lines.len(), 4, "{tree}");
+    assert!(lines[1].starts_with("  fetch (x3) ["), "{tree}");
+    assert!(lines[1].contains(".."), "{tree}");
+    assert!(lines[2].starts_with("  check ["), "{tree}");
+    assert!(lines[2].ends_with("<== current"), "{tree}");
+    // The single span is not collapsed.
+    assert!(lines[3].starts_with("  fetch ["), "{tree}");
+    assert!(!lines[3].contains(".."), "{tree}");
+}
+
+#[tokio::test]
+async fn test_show_output_type() {
+    let config = ConfigBuilder::default()
+        .show_output_type(true)
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+
+    let path = registry
+        .register((), "root")
+        .instrument(async {
+            async { current_tree().unwrap().current_path() }
+                .instrument_await("path")
+                .await
+        })
+        .await;
+    let expected = format!("path -> {}", std::any::type_name::<Vec<Span>>());
+    assert_eq!(path[1].to_string(), expected);
+
+    // Renaming keeps the output type.
+    let path = registry
+        .register((), "root")
+        .instrument(async {
+            async {
+                rename_current_span("renamed");
+                current_tree().unwrap().current_path()
+            }
+            .instrument_await("path")
+            .await
+        })
+        .await;
+    let expected = format!("renamed -> {}", std::any::type_name::<Vec<Span>>());
+    assert_eq!(path[1].to_string(), expected);
+}
+
+#[test]
+fn test_inline_span() {
+    let name = "a".repeat(Span::INLINE_CAPACITY);
+    assert!(Span::from_inline(&name).name.is_inline());
+    assert!(Span::from(name.clone()).name.is_inline());
+
+    let name = "a".repeat(Span::INLINE_CAPACITY + 1);
+    assert!(Span::from(name.as_str()).name.is_heap());
+    assert!(Span::from_static("a static name longer than the capacity")
+        .name
+        .is_static());
+    assert!(std::panic::catch_unwind(|| Span::from_inline(&name)).is_err());
+}
+
+#[cfg(feature = "location")]
+#[tokio::test]
+async fn test_show_location() {
+    for show_location in [false, true] {
+        let config = ConfigBuilder::default()
+            .show_location(show_location)
+            .build()
+            .unwrap();
+        let registry = Registry::new(config);
+
+        let (tree, line) = registry
+            .register((), "root")
+            .instrument(async {
+                let future = async { current_tree().unwrap() };
+                (future.instrument_await("poll").await, line!())
+            })
+            .await;
+        let tree = tree.to_string();
+
+        let location = format!("poll ({}:{line})", file!());
+        assert_eq!(tree.contains(&location), show_location, "{tree}");
+    }
+}
+
+#[tokio::test]
+async fn test_strict() {
+    let registry = Registry::new(ConfigBuilder::default().strict(true).build().unwrap());
+
+    // A clean tree passes the check.
+    registry
+        .register(1, "clean")
+        .instrument(async { ready(()).instrument_await("ready").await })
+        .await;
+
+    // Leak an instrumented future that has been polled, so its span is never removed.
+    let leaky = registry.register(2, "leaky").instrument(async {
+        let mut fut = Box::pin(pending::<()>().instrument_await("leaked"));
+        assert!(poll_fn(|cx| Poll::Ready(fut.as_mut().poll(cx)))
+            .await
+            .is_pending());
+        std::mem::forget(fut);
+    });
+    let Err(error) = tokio::spawn(leaky).await else {
+        panic!("expected a panic");
+    };
+    let panic = error.into_panic();
+    let message = panic.downcast_ref::<String>().unwrap();
+    assert!(message.contains("leaked"), "{message}");
+}
+
+#[tokio::test]
+async fn test_display_with_wall_clock() {
+    let registry = Registry::new(Config::default());
+    let _root = registry.register((), "root");
+
+    let tree = registry.get(()).unwrap();
+    let display = tree.display_with_wall_clock().to_string();
+    let (elapsed, since) = display
+        .trim_end()
+        .strip_prefix("root [")
+        .and_then(|s| s.strip_suffix(']'))
+        .and_then(|s| s.split_once(", since "))
+        .unwrap_or_else(|| panic!("{display}"));
+    assert!(elapsed.ends_with('s'), "{elapsed}");
+    assert!(since.starts_with("20") && since.ends_with('Z'), "{since}");
+}
+
+#[tokio::test]
+async fn test_current_path() {
+    let registry = Registry::new(Config::default());
+
+    let path = registry
+        .register((), "root")
+        .instrument(async {
+            async { current_tree().unwrap().current_path() }
+                .instrument_await("leaf")
+                .instrument_await("middle")
+                .await
+        })
+        .await;
+    let path = path.iter().map(|s| s.to_string()).collect_vec();
+    assert_eq!(path, ["root", "middle", "leaf"]);
+
+    let path = registry
+        .register((), "root")
+        .instrument(async { current_tree().unwrap().current_path() })
+        .await;
+    assert_eq!(path.len(), 1);
+}
+
+#[tokio::test]
+async fn test_current_task_id() {
+    let registry = Registry::new(Config::default());
+    assert_eq!(current_task_id(), None);
+
+    let id = |key| {
+        registry.register(key, "root").instrument(async {
+            let id = current_task_id().unwrap();
+            async { assert_eq!(current_task_id(), Some(id)) }
+                .instrument_await("child")
+                .await;
+            id
+        })
+    };
+    assert_ne!(id(1).await, id(2).await);
+}
+
+#[tokio::test]
+async fn test_on_context_mismatch() {
+    let mismatches = Arc::new(Mutex::new(Vec::new()));
+    let mismatches_clone = mismatches.clone();
+    let config = ConfigBuilder::default()
+        .on_context_mismatch(Arc::new(move |span: &Span, first, current| {
+            mismatches_clone
+                .lock()
+                .unwrap()
+                .push((span.to_string(), first, current));
+        }))
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+
+    let mut moved = Box::pin(pending::<()>().instrument_await("moved"));
+    let mut ids = Vec::new();
+    for key in [1, 2] {
+        let id = registry
+            .register(key, "root")
+            .instrument(async {
+                let poll = poll_fn(|cx| Poll::Ready(moved.as_mut().poll(cx))).await;
+                assert!(poll.is_pending());
+                current_task_id().unwrap()
+            })
+            .await;
+        ids.push(id);
+    }
+
+    assert_eq!(
+        *mismatches.lock().unwrap(),
+        [("moved".to_owned(), ids[0], ids[1])]
+    );
+}
+
+#[tokio::test]
+async fn test_rename_current_span() {
+    // No-op if not instrumented.
+    rename_current_span("nothing");
+
+    let registry = Registry::new(Config::default());
+
+    let path = registry
+        .register((), "root")
+        .instrument(async {
+            async {
+                rename_current_span("leaf renamed");
+                sleep(10).await;
+                current_tree().unwrap().current_path()
+            }
+            .instrument_await("leaf".category("io").order(1))
+            .instrument_await("middle")
+            .await
+        })
+        .await;
+    assert_eq!(path[2], "leaf renamed".category("io").order(1));
+    let path = path.iter().map(|s| s.to_string()).collect_vec();
+    assert_eq!(path, ["root", "middle", "leaf renamed"]);
+}
+
+#[tokio::test]
+async fn test_set_current_progress() {
+    // No-op if not instrumented.
+    set_current_progress(0.5);
+
+    let registry = Registry::new(Config::default());
+
+    let tree = registry
+        .register((), "root")
+        .instrument(async {
+            async {
+                set_current_progress(0.2);
+                set_current_progress(0.474);
+                current_tree().unwrap()
+            }
+            .instrument_await("compact")
+            .await
+        })
+        .await;
+    let progress = tree.nodes().map(|n| n.progress()).collect_vec();
+    assert_eq!(progress, [None, Some(0.474)]);
+    let tree = tree.to_string();
+    let lines = tree.lines().collect_vec();
+    assert!(!lines[0].contains('%'), "{tree}");
+    assert!(lines[1].ends_with(", 47%]  <== current"), "{tree}");
+}
+
+#[tokio::test]
+async fn test_poll_after_ready() {
+    let registry = Registry::new(Config::default());
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            let future = poll_fn(|_| Poll::Ready(())).instrument_await("ready");
+            pin_mut!(future);
+            poll_fn(|cx| {
+                assert!(future.as_mut().poll(cx).is_ready());
+                assert!(future.as_mut().poll(cx).is_ready());
+                Poll::Ready(())
+            })
+            .await;
+            assert_eq!(current_tree().unwrap().current_path(), ["root".into()]);
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn test_instrument_await_if() {
+    let registry = Registry::new(Config::default());
+
+    let paths = registry
+        .register((), "root")
+        .instrument(async {
+            let path = || async { current_tree().unwrap().current_path() };
+            let futures = [true, false].map(|cond| path().instrument_await_if(cond, "sampled"));
+            join_all(futures).await
+        })
+        .await;
+    assert_eq!(
+        paths,
+        [vec!["root".into(), "sampled".into()], vec!["root".into()]]
+    );
+}
+
+#[tokio::test]
+async fn test_instrument_boxed() {
+    let registry = Registry::new(Config::default());
+    let root = registry.register((), "root");
+
+    root.instrument(async {
+        let futures = vec![
+            sleep(10).instrument_boxed("sleep"),
+            async { current_tree().unwrap().to_string() }
+                .map(|tree| assert!(tree.contains("tree"), "{tree}"))
+                .instrument_boxed("tree"),
+        ];
+        join_all(futures).await;
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_on_span_close() {
+    let closed = Arc::new(Mutex::new(Vec::new()));
+    let closed_clone = closed.clone();
+    let config = ConfigBuilder::default()
+        .on_span_close(Arc::new(move |span: &crate::Span, _elapsed| {
+            // Accessing the current tree in the callback should not deadlock.
+            let depth = current_tree().unwrap().current_path().len();
+            closed_clone.lock().unwrap().push((span.to_string(), depth));
+        }))
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            join_all([
+                sleep(10).instrument_await("sleep 10"),
+                sleep(20).instrument_await("sleep 20"),
+            ])
+            .instrument_await("join")
+            .await;
+
+            // Cancelled spans are not closed.
+            select_all([
+                sleep(10).boxed().instrument_await("sleep 10"),
+                sleep(1000).boxed().instrument_await("sleep 1000"),
+            ])
+            .await;
+        })
+        .await;
+
+    let closed = closed.lock().unwrap().clone();
+    assert_eq!(
+        closed,
+        [
+            ("sleep 10".to_owned(), 2),
+            ("sleep 20".to_owned(), 2),
+            ("join".to_owned(), 1),
+            ("sleep 10".to_owned(), 1),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_with_parent_span() {
+    let registry = Registry::new(Config::default());
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            let (tx, rx) = futures::channel::oneshot::channel();
+            let owner = async {
+                tx.send(current_span_handle().unwrap()).unwrap();
+                sleep(200).await;
+            }
+            .instrument_await("owner");
+
+            let helper = async {
+                let parent = rx.await.unwrap();
+                with_parent_span(parent, sleep(100).instrument_await("work")).await;
+            }
+            .instrument_await("helper");
+
+            let check = async {
+                sleep(50).await;
+                let tree = registry.get(()).unwrap();
+                let work = tree.find(|s| s.to_string() == "work");
+                assert_eq!(work.len(), 1);
+                let tree = tree.to_string();
+                assert!(
+                    tree.contains("owner [") && tree.contains("helper ["),
+                    "{tree}"
+                );
+                let lines = tree.lines().collect_vec();
+                let owner = lines.iter().position(|l| l.contains("owner")).unwrap();
+                assert!(lines[owner + 1].contains("work"), "{tree}");
+            };
+
+            join3(owner, helper, check).await;
+        })
+        .await;
+
+    // Remounting under a span of a different context is rejected.
+    let parent = registry
+        .register((), "root")
+        .instrument(async { current_span_handle().unwrap() })
+        .await;
+    let path = registry
+        .register((), "other")
+        .instrument(async {
+            with_parent_span(
+                parent,
+                async { current_tree().unwrap().current_path() }.instrument_await("work"),
+            )
+            .await
+        })
+        .await;
+    assert_eq!(path, ["other".into(), "work".into()]);
+
+    // Remounting under the span of the future itself or its descendant is rejected.
+    registry
+        .register((), "root")
+        .instrument(async {
+            let (tx, rx) = futures::channel::oneshot::channel();
+            let mut work = Box::pin(
+                async {
+                    async {
+                        tx.send(current_span_handle().unwrap()).unwrap();
+                        sleep(50).await;
+                    }
+                    .instrument_await("inner")
+                    .await;
+                }
+                .instrument_await("work"),
+            );
+            assert!(poll_fn(|cx| Poll::Ready(work.as_mut().poll(cx)))
+                .await
+                .is_pending());
+
+            let itself = work.span_handle().unwrap();
+            assert!(poll_fn(|cx| Poll::Ready(
+                with_parent_span(itself, work.as_mut()).poll_unpin(cx)
+            ))
+            .await
+            .is_pending());
+
+            let descendant = rx.await.unwrap();
+            with_parent_span(descendant, work).await;
+        })
+        .await;
+}
@@ -0,0 +1,68 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::sleep;
+
+use crate::root::current_context;
+use crate::{current_tree, Config, InstrumentAwait, InstrumentIo, Registry};
+
+#[tokio::test]
+async fn main() {
+    let registry = Registry::new(Config::default());
+    let (client, server) = tokio::io::duplex(64);
+
+    let handle = tokio::spawn(registry.register((), "root").instrument(async move {
+        let mut reader = server.instrument_read("read");
+        let mut buf = [0; 4];
+
+        reader
+            .read_exact(&mut buf)
+            .instrument_await("read 1")
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // The span of the reader persists across reads.
+        let tree = current_tree().unwrap().to_string();
+        assert!(tree.contains("read ["), "{tree}");
+
+        reader
+            .read_exact(&mut buf)
+            .instrument_await("read 2")
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // Cleaned up on drop.
+        drop(reader);
+        assert_eq!(current_context().unwrap().tree().active_node_count(), 1);
+    }));
+
+    let mut writer = client.instrument_write("write");
+    sleep(Duration::from_millis(100)).await;
+
+    // The reader is pending under the `read 1` span.
+    let tree = registry.get(()).unwrap().to_string();
+    assert!(tree.contains("read 1"), "{tree}");
+    assert!(tree.contains("    read ["), "{tree}");
+
+    writer.write_all(b"ping").await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    writer.write_all(b"pong").await.unwrap();
+
+    handle.await.unwrap();
+}
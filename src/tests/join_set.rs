@@ -0,0 +1,57 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::pending;
+use tokio::time::sleep;
+
+use crate::{Config, InstrumentAwait, Registry, TrackedJoinSet};
+
+#[tokio::test]
+async fn main() {
+    let registry = Registry::new(Config::default());
+    let mut set = TrackedJoinSet::new(registry.clone());
+    let mut other = TrackedJoinSet::new(registry.clone());
+
+    let (tx, rx) = oneshot::channel::<()>();
+    set.spawn("short", async move {
+        rx.instrument_await("wait").await.unwrap();
+    });
+    set.spawn("long", pending::<()>());
+    other.spawn("other", pending::<()>());
+
+    sleep(Duration::from_millis(100)).await;
+
+    let trees = set.trees();
+    assert_eq!(trees.len(), 2);
+    assert!(trees[0].to_string().starts_with("short"));
+    assert!(trees[1].to_string().starts_with("long"));
+    assert_eq!(other.trees().len(), 1);
+    assert_eq!(registry.collect_all().len(), 3);
+
+    tx.send(()).unwrap();
+    set.join_next().await.unwrap().unwrap();
+
+    let trees = set.trees();
+    assert_eq!(trees.len(), 1);
+    assert!(trees[0].to_string().starts_with("long"));
+
+    set.abort_all();
+    assert!(set.join_next().await.unwrap().unwrap_err().is_cancelled());
+    assert!(set.is_empty());
+    assert!(set.trees().is_empty());
+    assert_eq!(other.trees().len(), 1);
+}
@@ -0,0 +1,256 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use futures::future::{join, pending, select};
+use futures::{stream, FutureExt, StreamExt};
+use itertools::Itertools;
+use serde_json::Value;
+use tokio::time::sleep;
+
+use crate::{Config, InstrumentAwait, InstrumentTryStream, Registry, SpanExt, Tree};
+
+/// Strip the times, the ids of all nodes, and the task id from the serialized tree, as the ids are
+/// not preserved on deserialization.
+fn strip_elapsed(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.remove("elapsed_ns");
+            map.remove("id");
+            map.remove("current");
+            map.remove("captured_at_ns");
+            map.remove("task_id");
+            map.values_mut().for_each(strip_elapsed);
+        }
+        Value::Array(values) => values.iter_mut().for_each(strip_elapsed),
+        _ => {}
+    }
+}
+
+async fn work() {
+    let mut fut = pending::<()>().instrument_await("fut");
+
+    // Make `fut` detached.
+    let _ = select(
+        sleep(Duration::from_millis(10))
+            .instrument_await("sleep")
+            .boxed(),
+        &mut fut,
+    )
+    .instrument_await("select")
+    .await;
+
+    // Keep an unpolled future alive, recorded if `record_unpolled` is set.
+    let _starved = pending::<()>().instrument_await_eager("starved");
+
+    // Keep a failed stream alive.
+    let mut failing = stream::iter([Err::<(), _>(())]).instrument_try_stream("failing");
+    let _ = failing.next().await;
+
+    join(
+        pending::<()>().instrument_await("pending 1".with_id(42)),
+        pending::<()>().instrument_await("pending 2"),
+    )
+    .instrument_await("join")
+    .await;
+}
+
+#[cfg(not(feature = "disabled"))]
+#[tokio::test]
+async fn test_round_trip() {
+    use crate::ConfigBuilder;
+
+    let config = ConfigBuilder::default()
+        .record_unpolled(true)
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+    tokio::spawn(registry.register((), "root").instrument(work()));
+    sleep(Duration::from_millis(100)).await;
+
+    let tree = registry.get(()).unwrap();
+    let json = serde_json::to_string(&tree).unwrap();
+
+    let deserialized: Tree = serde_json::from_str(&json).unwrap();
+    let json_2 = serde_json::to_string(&deserialized).unwrap();
+
+    let mut value: Value = serde_json::from_str(&json).unwrap();
+    let mut value_2: Value = serde_json::from_str(&json_2).unwrap();
+    assert_eq!(value["detached"].as_array().unwrap().len(), 1);
+    assert!(value["task_id"].is_u64());
+    assert!(json.contains(r#""span":"pending 1","elapsed_ns""#));
+    let user_ids = deserialized
+        .nodes()
+        .filter_map(|n| n.span().user_id())
+        .collect_vec();
+    assert_eq!(user_ids, [42]);
+    let failed = deserialized
+        .nodes()
+        .filter(|n| n.is_failed())
+        .map(|n| n.span().name())
+        .collect_vec();
+    assert_eq!(failed, ["failing"]);
+    assert!(deserialized.to_string().contains("failing (failed)"));
+    let unpolled = deserialized
+        .nodes()
+        .filter(|n| !n.is_polled())
+        .map(|n| n.span().name())
+        .collect_vec();
+    assert_eq!(unpolled, ["starved"]);
+    assert!(deserialized
+        .to_string()
+        .contains("starved (not yet polled)"));
+    assert_eq!(deserialized.task_id(), None);
+    strip_elapsed(&mut value);
+    strip_elapsed(&mut value_2);
+    assert_eq!(value, value_2);
+    assert_eq!(deserialized.current_path(), tree.current_path());
+
+    // The deserialized tree is frozen.
+    let display = deserialized.to_string();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(deserialized.to_string(), display);
+    assert_eq!(display.lines().count(), tree.to_string().lines().count());
+}
+
+#[tokio::test]
+async fn test_serialize_stream() {
+    let registry = Registry::new(Config::default());
+    tokio::spawn(registry.register((), "root").instrument(work()));
+    tokio::spawn(registry.register("foo", "foo").instrument(work()));
+    sleep(Duration::from_millis(100)).await;
+    let other = Registry::new(Config::default());
+    let _bar = other.register("bar", "bar");
+    registry.import(other.collect_all());
+
+    let mut buf = Vec::new();
+    registry
+        .serialize_stream(&mut serde_json::Serializer::new(&mut buf))
+        .unwrap();
+    let mut value: Value = serde_json::from_slice(&buf).unwrap();
+    strip_elapsed(&mut value);
+
+    let mut expected = registry
+        .collect_all()
+        .into_iter()
+        .map(|(key, tree)| serde_json::json!({ "key": key.to_string(), "tree": tree }))
+        .collect_vec();
+    expected.iter_mut().for_each(strip_elapsed);
+    let mut entries = value.as_array().unwrap().clone();
+    let by_key = |v: &Value| v["key"].as_str().unwrap().to_owned();
+    entries.sort_by_key(by_key);
+    expected.sort_by_key(by_key);
+    assert_eq!(entries, expected);
+    assert_eq!(entries.len(), 3);
+}
+
+#[tokio::test]
+async fn test_round_trip_binary() {
+    let registry = Registry::new(Config::default());
+    tokio::spawn(registry.register((), "root").instrument(work()));
+    sleep(Duration::from_millis(100)).await;
+
+    let tree = registry.get(()).unwrap();
+    let json = serde_json::to_string(&tree).unwrap();
+    let mut value: Value = serde_json::from_str(&json).unwrap();
+    strip_elapsed(&mut value);
+
+    let bincode = bincode::serialize(&tree).unwrap();
+    let postcard = postcard::to_stdvec(&tree).unwrap();
+    assert!(bincode.len() < json.len());
+    assert!(postcard.len() < bincode.len());
+
+    let deserialized = [
+        bincode::deserialize::<Tree>(&bincode).unwrap(),
+        postcard::from_bytes::<Tree>(&postcard).unwrap(),
+    ];
+    for deserialized in deserialized {
+        let mut value_2 = serde_json::to_value(&deserialized).unwrap();
+        strip_elapsed(&mut value_2);
+        assert_eq!(value, value_2);
+    }
+}
+
+#[test]
+fn test_invalid() {
+    let json = r#"{"current":3,"captured_at_ns":0,"tree":{"id":1,"span":"root","elapsed_ns":0,"children":[]},"detached":[]}"#;
+    assert!(serde_json::from_str::<Tree>(json).is_err());
+
+    let json = r#"{"current":1,"captured_at_ns":0,"tree":{"id":1,"span":"root","elapsed_ns":0,"children":[{"id":1,"span":"dup","elapsed_ns":0,"children":[]}]},"detached":[]}"#;
+    assert!(serde_json::from_str::<Tree>(json).is_err());
+}
+
+#[test]
+fn test_sparse_ids() {
+    let json = format!(
+        r#"{{"current":{max},"captured_at_ns":0,"tree":{{"id":1000000000,"span":"root","elapsed_ns":0,"children":[{{"id":{max},"span":"child","elapsed_ns":0,"children":[]}}]}},"detached":[]}}"#,
+        max = usize::MAX
+    );
+    let tree = serde_json::from_str::<Tree>(&json).unwrap();
+    assert_eq!(tree.current_path(), ["root".into(), "child".into()]);
+
+    let value = serde_json::to_value(&tree).unwrap();
+    assert_eq!(value["tree"]["id"], 1);
+    assert_eq!(value["current"], 2);
+}
+
+#[tokio::test]
+async fn test_wall_clock() {
+    /// Parse the millisecond of the day of the wall-clock time of the root span.
+    fn root_since_ms(tree: &Tree) -> u64 {
+        let display = tree.display_with_wall_clock().to_string();
+        let time = display.lines().next().unwrap().split('T').nth(1).unwrap();
+        let (hms, ms) = time.trim_end_matches("Z]").split_once('.').unwrap();
+        let hms = hms.split(':').map(|x| x.parse::<u64>().unwrap());
+        hms.fold(0, |acc, x| acc * 60 + x) * 1000 + ms.parse::<u64>().unwrap()
+    }
+
+    let registry = Registry::new(Config::default());
+    let _root = registry.register((), "root");
+    sleep(Duration::from_millis(500)).await;
+
+    let tree = registry.get(()).unwrap();
+    let json = serde_json::to_string(&tree).unwrap();
+    sleep(Duration::from_millis(500)).await;
+    let deserialized: Tree = serde_json::from_str(&json).unwrap();
+
+    // The wall-clock start time is preserved, instead of being the time of deserialization.
+    let (expected, actual) = (root_since_ms(&tree), root_since_ms(&deserialized));
+    assert!(expected.abs_diff(actual) < 100, "{expected} vs {actual}");
+}
+
+#[test]
+fn test_ancient_span() {
+    let json = format!(
+        r#"{{"current":2,"captured_at_ns":{max},"tree":{{"id":1,"span":"root","elapsed_ns":{max},"children":[{{"id":2,"span":"child","elapsed_ns":1000000000,"children":[]}}]}},"detached":[]}}"#,
+        max = u64::MAX
+    );
+    let tree = serde_json::from_str::<Tree>(&json).unwrap();
+
+    // The elapsed time of the ancient span is saturated, instead of falling back to zero.
+    let elapsed = tree
+        .nodes()
+        .map(|n| (n.span().name().to_owned(), n.elapsed()))
+        .collect_vec();
+    assert_eq!(elapsed[0].0, "root");
+    assert!(elapsed[0].1 > Duration::from_secs(99 * 365 * 86400));
+    assert_eq!(elapsed[1], ("child".to_owned(), Duration::from_secs(1)));
+
+    // Formatting and serializing do not panic.
+    let _ = tree.to_string();
+    let _ = tree.display_with_wall_clock().to_string();
+    let value = serde_json::to_value(&tree).unwrap();
+    assert!(value["tree"]["elapsed_ns"].as_u64().unwrap() > 99 * 365 * 86400 * 1_000_000_000);
+}
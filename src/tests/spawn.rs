@@ -15,9 +15,11 @@
 use std::time::Duration;
 
 use futures::future::pending;
+use itertools::Itertools;
+use tokio::runtime::Handle;
 use tokio::time::sleep;
 
-use crate::{Config, InstrumentAwait, Registry};
+use crate::{current_tree, Config, InstrumentAwait, Registry};
 
 #[tokio::test]
 async fn main() {
@@ -43,3 +45,170 @@ async fn main() {
     assert_eq!(registry.collect_anonymous().len(), 2);
     assert_eq!(registry.collect_all().len(), 3);
 }
+
+#[cfg(not(feature = "disabled"))]
+#[tokio::test]
+async fn test_recent_aborted() {
+    use crate::ConfigBuilder;
+
+    let config = ConfigBuilder::default()
+        .aborted_capacity(2)
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+
+    for i in 0..3 {
+        let handle = tokio::spawn(registry.register(i, format!("task {i}")).instrument(async {
+            pending::<()>().instrument_await("stuck").await;
+        }));
+        sleep(Duration::from_millis(100)).await;
+        handle.abort();
+        assert!(handle.await.unwrap_err().is_cancelled());
+    }
+
+    // Completed tasks are not recorded.
+    registry.register(3, "task 3").instrument(async {}).await;
+
+    let aborted = registry.recent_aborted();
+    assert_eq!(aborted.len(), 2);
+    for ((key, tree), i) in aborted.iter().zip(1..) {
+        assert_eq!(key.downcast_ref::<i32>(), Some(&i));
+        let tree = tree.to_string();
+        assert!(tree.contains(&format!("task {i}")), "{tree}");
+        assert!(tree.contains("stuck"), "{tree}");
+    }
+}
+
+#[tokio::test]
+async fn test_spawn_local() {
+    let registry = Registry::new(Config::default());
+    let local = tokio::task::LocalSet::new();
+
+    local
+        .run_until(registry.register((), "root").instrument(async {
+            // `Rc` makes the future `!Send`.
+            let rc = std::rc::Rc::new(());
+            crate::spawn_local("child", "child", async move {
+                let _rc = rc;
+                pending::<()>().await;
+            });
+            sleep(Duration::from_millis(100)).await;
+        }))
+        .await;
+
+    let tree = registry.get("child").unwrap().to_string();
+    assert!(tree.starts_with("child"), "{tree}");
+}
+
+#[test]
+fn test_spawn_on() {
+    let registry = Registry::new(Config::default());
+    let io = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("io")
+        .enable_all()
+        .build()
+        .unwrap();
+    let compute = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let thread_name = compute.block_on(registry.register((), "root").instrument(async {
+        crate::spawn_on(io.handle(), "child", "child", pending::<()>());
+        registry
+            .spawn_on(io.handle(), "grandchild", "grandchild", async {
+                std::thread::current().name().map(str::to_owned)
+            })
+            .await
+            .unwrap()
+    }));
+    assert_eq!(thread_name.as_deref(), Some("io"));
+
+    assert!(registry.get("child").is_some());
+}
+
+#[tokio::test]
+async fn test_task_id() {
+    let registry = Registry::new(Config::default());
+
+    let handle = registry.spawn_on(&Handle::current(), "child", "child", async {
+        let tree = current_tree().unwrap();
+        assert_eq!(tree.task_id(), Some(tokio::task::id()));
+        tree.task_id()
+    });
+    let id = handle.id();
+    assert_eq!(handle.await.unwrap(), Some(id));
+}
+
+#[cfg(not(feature = "disabled"))]
+#[tokio::test]
+async fn test_slowest() {
+    use crate::SlowestBy;
+
+    let registry = Registry::new(Config::default());
+    let spawn = |key: &'static str, delay: u64| {
+        tokio::spawn(registry.register(key, key).instrument(async move {
+            sleep(Duration::from_millis(delay)).await;
+            pending::<()>().instrument_await("leaf").await
+        }))
+    };
+
+    // The task started first gets stuck at the leaf last.
+    let old = spawn("old", 60);
+    sleep(Duration::from_millis(30)).await;
+    let stuck = spawn("stuck", 0);
+    sleep(Duration::from_millis(60)).await;
+    let fresh = spawn("fresh", 0);
+    sleep(Duration::from_millis(10)).await;
+
+    let slowest = |n, by| {
+        registry
+            .slowest(n, by)
+            .into_iter()
+            .map(|(k, _)| *k.downcast_ref::<&str>().unwrap())
+            .collect_vec()
+    };
+    assert_eq!(slowest(2, SlowestBy::RootElapsed), ["old", "stuck"]);
+    assert_eq!(slowest(2, SlowestBy::MaxLeafElapsed), ["stuck", "old"]);
+    assert_eq!(
+        slowest(10, SlowestBy::RootElapsed),
+        ["old", "stuck", "fresh"]
+    );
+    assert!(slowest(0, SlowestBy::RootElapsed).is_empty());
+
+    for handle in [old, stuck, fresh] {
+        handle.abort();
+    }
+}
+
+#[tokio::test]
+async fn test_register_child() {
+    let registry = Registry::new(Config::default());
+
+    registry
+        .register("parent", "parent")
+        .instrument(async {
+            let child = registry.register_child("parent", "child", "child root");
+            let handle = tokio::spawn(child.instrument(pending::<()>().instrument_await("work")));
+            sleep(Duration::from_millis(100)).await;
+
+            let tree = registry.get("parent").unwrap().to_string();
+            let lines = tree.lines().collect_vec();
+            assert_eq!(lines.len(), 2, "{tree}");
+            assert_eq!(lines[1], "  -> [child]", "{tree}");
+            let child_tree = registry.get("child").unwrap().to_string();
+            assert!(child_tree.starts_with("child root"), "{child_tree}");
+
+            // The link is removed when the child is dropped.
+            handle.abort();
+            let _ = handle.await;
+            let tree = registry.get("parent").unwrap().to_string();
+            assert_eq!(tree.lines().count(), 1, "{tree}");
+        })
+        .await;
+
+    // Equivalent to `register` if the parent does not exist.
+    let _child = registry.register_child("parent", "child", "child root");
+    assert!(registry.get("child").is_some());
+}
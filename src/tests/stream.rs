@@ -0,0 +1,112 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::{stream, StreamExt};
+
+use crate::root::current_context;
+use crate::{current_tree, Config, InstrumentStream, InstrumentTryStream, Registry};
+
+#[tokio::test]
+async fn test_try_stream() {
+    let registry = Registry::new(Config::default());
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            let mut ok = stream::iter([Ok::<_, ()>(1), Ok(2)]).instrument_try_stream("ok");
+            let mut failing =
+                stream::iter([Ok(1), Err(()), Ok(3)]).instrument_try_stream("failing");
+
+            assert_eq!(ok.next().await, Some(Ok(1)));
+            assert_eq!(failing.next().await, Some(Ok(1)));
+            let tree = current_tree().unwrap();
+            assert!(tree.nodes().all(|n| !n.is_failed()), "{tree}");
+
+            assert_eq!(ok.next().await, Some(Ok(2)));
+            let tree = current_tree().unwrap().to_string();
+            assert!(tree.contains("  ok ["), "{tree}");
+            // Popped once the stream is exhausted.
+            assert_eq!(ok.next().await, None);
+            let tree = current_tree().unwrap().to_string();
+            assert!(!tree.contains("  ok ["), "{tree}");
+
+            assert_eq!(failing.next().await, Some(Err(())));
+            // The span persists across polls, and is marked as failed after the error.
+            let tree = current_tree().unwrap();
+            let failed = tree
+                .nodes()
+                .filter(|n| n.is_failed())
+                .map(|n| n.span().name().to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(failed, ["failing"], "{tree}");
+            let tree = tree.to_string();
+            assert!(tree.contains("  failing (failed) ["), "{tree}");
+
+            // Cleaned up on drop.
+            drop((ok, failing));
+            assert_eq!(current_context().unwrap().tree().active_node_count(), 1);
+
+            // Popped once exhausted, even after an error.
+            let mut failing = stream::iter([Err::<(), _>(())]).instrument_try_stream("failing");
+            assert_eq!(failing.next().await, Some(Err(())));
+            assert_eq!(current_context().unwrap().tree().active_node_count(), 2);
+            assert_eq!(failing.next().await, None);
+            assert_eq!(current_context().unwrap().tree().active_node_count(), 1);
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn test_stream() {
+    let registry = Registry::new(Config::default());
+
+    registry
+        .register((), "root")
+        .instrument(async {
+            let mut numbers = stream::iter([1, 2, 3])
+                .map(|i| {
+                    let tree = current_tree().unwrap().to_string();
+                    // The span is current while the stream is polled.
+                    assert!(
+                        tree.contains("  numbers [") && tree.contains("]  <== current"),
+                        "{tree}"
+                    );
+                    i
+                })
+                .instrument_await_stream("numbers");
+
+            for i in 1..=3 {
+                assert_eq!(numbers.next().await, Some(i));
+                // The span persists across the yielded items, and is not current in between.
+                let tree = current_tree().unwrap().to_string();
+                assert!(tree.contains("  numbers ["), "{tree}");
+                assert!(!tree.contains("<== current"), "{tree}");
+            }
+
+            // Popped once the stream is exhausted.
+            assert_eq!(numbers.next().await, None);
+            assert_eq!(current_context().unwrap().tree().active_node_count(), 1);
+            assert_eq!(numbers.next().await, None);
+            drop(numbers);
+            assert_eq!(current_context().unwrap().tree().active_node_count(), 1);
+
+            // Cleaned up on drop if not exhausted.
+            let mut numbers = stream::iter([1, 2]).instrument_await_stream("numbers");
+            assert_eq!(numbers.next().await, Some(1));
+            assert_eq!(current_context().unwrap().tree().active_node_count(), 2);
+            drop(numbers);
+            assert_eq!(current_context().unwrap().tree().active_node_count(), 1);
+        })
+        .await;
+}
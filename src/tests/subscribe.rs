@@ -0,0 +1,143 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures::future::{pending, ready, select};
+use futures::StreamExt;
+
+use crate::{Config, ConfigBuilder, InstrumentAwait, Registry, TreeEvent};
+
+#[tokio::test]
+async fn test_subscribe() {
+    let registry = Registry::new(Config::default());
+    let mut events = registry.subscribe();
+
+    registry
+        .register("task", "root")
+        .instrument(async {
+            tokio::task::yield_now()
+                .instrument_await("yield")
+                .instrument_await("outer")
+                .await;
+        })
+        .await;
+    drop(registry);
+
+    let events = events.by_ref().collect::<Vec<_>>().await;
+    let mut opened = Vec::new();
+    let mut closed = Vec::new();
+    for event in events {
+        match event {
+            TreeEvent::SpanOpened { key, node_id, span } => {
+                assert_eq!(key.to_string(), "task");
+                opened.push((node_id, span.to_string()));
+            }
+            TreeEvent::SpanClosed { node_id, .. } => closed.push(node_id),
+        }
+    }
+    assert_eq!(
+        opened.iter().map(|(_, s)| s.as_str()).collect::<Vec<_>>(),
+        ["outer", "yield"]
+    );
+    // Closed in the reverse order.
+    assert_eq!(closed, [opened[1].0, opened[0].0]);
+}
+
+#[tokio::test]
+async fn test_subscribe_evicted() {
+    let config = ConfigBuilder::default().max_detached(0).build().unwrap();
+    let registry = Registry::new(config);
+    let mut events = registry.subscribe();
+
+    registry
+        .register("task", "root")
+        .instrument(async {
+            let mut fut = Box::pin(pending::<()>().instrument_await("detached"));
+            // `fut` is detached when `select` is ready, then evicted immediately.
+            select(&mut fut, ready(())).instrument_await("select").await;
+        })
+        .await;
+    drop(registry);
+
+    let events = events.by_ref().collect::<Vec<_>>().await;
+    let mut opened = HashSet::new();
+    let mut closed = HashSet::new();
+    for event in events {
+        match event {
+            TreeEvent::SpanOpened { node_id, .. } => assert!(opened.insert(node_id)),
+            TreeEvent::SpanClosed { node_id, .. } => assert!(closed.insert(node_id)),
+        }
+    }
+    assert_eq!(opened.len(), 2);
+    assert_eq!(opened, closed);
+}
+
+#[tokio::test]
+async fn test_subscribe_lagged() {
+    let config = ConfigBuilder::default()
+        .subscriber_capacity(2)
+        .build()
+        .unwrap();
+    let registry = Registry::new(config);
+    let mut events = registry.subscribe();
+
+    registry
+        .register("task", "root")
+        .instrument(async {
+            for _ in 0..3 {
+                async {}.instrument_await("span").await;
+            }
+        })
+        .await;
+
+    // 6 events in total, the oldest 4 are dropped.
+    assert_eq!(events.lagged(), 4);
+    assert!(matches!(
+        events.next().await,
+        Some(TreeEvent::SpanOpened { .. })
+    ));
+    assert!(matches!(
+        events.next().await,
+        Some(TreeEvent::SpanClosed { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_watch_key() {
+    let registry = Registry::new(Config::default());
+    assert!(registry
+        .watch_key("missing", Duration::from_millis(10))
+        .next()
+        .await
+        .is_none());
+
+    let task = tokio::spawn(registry.register("task", "root").instrument(async {
+        tokio::time::sleep(Duration::from_millis(200))
+            .instrument_await("sleep")
+            .await;
+    }));
+    let snapshots = registry
+        .watch_key("task", Duration::from_millis(50))
+        .collect::<Vec<_>>()
+        .await;
+    task.await.unwrap();
+
+    assert!(snapshots.len() >= 2, "{}", snapshots.len());
+    for tree in snapshots {
+        let tree = tree.to_string();
+        assert!(tree.starts_with("root"), "{tree}");
+    }
+}
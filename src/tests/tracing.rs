@@ -0,0 +1,111 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+use crate::{Config, InstrumentAwait, Registry};
+
+/// A subscriber recording the `span` field of the entered and exited spans.
+#[derive(Default)]
+struct Recorder {
+    next_id: AtomicU64,
+    names: Mutex<Vec<String>>,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Recorder {
+    fn name(&self, id: &Id) -> String {
+        self.names.lock().unwrap()[id.into_u64() as usize - 1].clone()
+    }
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        struct SpanField(String);
+        impl Visit for SpanField {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "span" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+
+        let mut name = SpanField(String::new());
+        attrs.record(&mut name);
+        self.names.lock().unwrap().push(name.0);
+        Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, _: &Event<'_>) {}
+
+    fn enter(&self, id: &Id) {
+        let entry = format!("enter {}", self.name(id));
+        self.log.lock().unwrap().push(entry);
+    }
+
+    fn exit(&self, id: &Id) {
+        let entry = format!("exit {}", self.name(id));
+        self.log.lock().unwrap().push(entry);
+    }
+}
+
+#[tokio::test]
+async fn test_tracing_bridge() {
+    let recorder = Recorder::default();
+    let log = recorder.log.clone();
+    let _guard = tracing::subscriber::set_default(recorder);
+
+    let registry = Registry::new(Config::default());
+    registry
+        .register((), "root")
+        .instrument(async {
+            async {
+                tokio::task::yield_now().instrument_await("yield").await;
+            }
+            .instrument_await("outer")
+            .await;
+        })
+        .await;
+
+    // Not recorded outside of a context.
+    async {}.instrument_await("no context").await;
+
+    let log = log.lock().unwrap().clone();
+    assert_eq!(
+        log,
+        [
+            "enter outer",
+            "enter yield",
+            "exit yield",
+            "exit outer",
+            "enter outer",
+            "enter yield",
+            "exit yield",
+            "exit outer",
+        ]
+    );
+}
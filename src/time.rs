@@ -0,0 +1,164 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The timing backend of the spans.
+//!
+//! By default, [`coarsetime`] is used for cheap timestamps with about millisecond granularity.
+//! With the `precise-time` feature enabled, [`std::time::Instant`] is used instead.
+
+use std::fmt::Display;
+use std::time::{Duration, SystemTime};
+
+#[cfg(not(feature = "precise-time"))]
+type Inner = coarsetime::Instant;
+#[cfg(feature = "precise-time")]
+type Inner = std::time::Instant;
+
+/// The maximum elapsed time of about 100 years, which can be added to or subtracted from the
+/// current time with all timing backends. Longer elapsed time, like the one from a clock bug, is
+/// saturated to this when it has to be converted back to a time point.
+#[cfg(feature = "serde")]
+pub(crate) const MAX_ELAPSED: Duration = Duration::from_secs(100 * 365 * 86400);
+
+/// Returns the number of nanoseconds of the duration, saturated to `u64::MAX` for durations longer
+/// than about 584 years.
+#[cfg(feature = "serde")]
+pub(crate) fn saturating_nanos(d: Duration) -> u64 {
+    d.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+/// A measurement of a monotonically nondecreasing clock, backed by the selected timing backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct Instant(Inner);
+
+// The conversions are only necessary for `coarsetime`.
+#[allow(clippy::useless_conversion)]
+impl Instant {
+    /// Returns the current time.
+    pub(crate) fn now() -> Self {
+        Self(Inner::now())
+    }
+
+    /// Returns the amount of time elapsed from `earlier` to this one, or zero if `earlier` is
+    /// later than this one.
+    pub(crate) fn duration_since(&self, earlier: Self) -> Duration {
+        self.0.duration_since(earlier.0).into()
+    }
+
+    /// Returns `Some(t)` where `t` is the time `self + duration` if it can be represented.
+    #[cfg(feature = "serde")]
+    pub(crate) fn checked_add(&self, duration: Duration) -> Option<Self> {
+        self.0.checked_add(duration.into()).map(Self)
+    }
+
+    /// Returns `Some(t)` where `t` is the time `self - duration` if it can be represented.
+    pub(crate) fn checked_sub(&self, duration: Duration) -> Option<Self> {
+        self.0.checked_sub(duration.into()).map(Self)
+    }
+
+    /// Converts a [`std::time::Instant`] to the time of the selected timing backend.
+    ///
+    /// Falls back to the current time if it cannot be represented.
+    pub(crate) fn from_std(instant: std::time::Instant) -> Self {
+        let now = Self::now();
+        let elapsed = std::time::Instant::now().saturating_duration_since(instant);
+        now.checked_sub(elapsed).unwrap_or(now)
+    }
+
+    /// Converts the time to the wall-clock time, given the anchor of the wall-clock time of an
+    /// instant.
+    ///
+    /// Saturates to the Unix epoch or the anchor if the wall-clock time cannot be represented.
+    pub(crate) fn to_system_time(self, (system, instant): (SystemTime, Self)) -> SystemTime {
+        if self <= instant {
+            let earlier = instant.duration_since(self);
+            system
+                .checked_sub(earlier)
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+        } else {
+            system
+                .checked_add(self.duration_since(instant))
+                .unwrap_or(system)
+        }
+    }
+}
+
+/// Formats the wall-clock time in RFC 3339 with millisecond precision in UTC, like
+/// `2023-11-14T22:13:20.123Z`. Times before the Unix epoch are formatted as the epoch.
+pub(crate) struct Rfc3339(pub(crate) SystemTime);
+
+impl Display for Rfc3339 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let since_epoch = self
+            .0
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+        // Convert the days since the epoch to the civil date, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            since_epoch.subsec_millis()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc3339() {
+        let format = |d| Rfc3339(SystemTime::UNIX_EPOCH + d).to_string();
+        assert_eq!(format(Duration::ZERO), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format(Duration::from_millis(1_700_000_000_123)),
+            "2023-11-14T22:13:20.123Z"
+        );
+        assert_eq!(
+            format(Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn test_to_system_time_saturating() {
+        let now = Instant::now();
+        let earlier = now.checked_sub(Duration::from_secs(1)).unwrap();
+
+        // Beyond the maximum wall-clock time.
+        let max = (0..64)
+            .rev()
+            .map(|shift| Duration::from_secs(1 << shift))
+            .fold(SystemTime::UNIX_EPOCH, |t, d| t.checked_add(d).unwrap_or(t));
+        let anchor = (max, earlier);
+        assert_eq!(now.to_system_time(anchor), max);
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2023 RisingWave Labs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use indextree::NodeId;
+use itertools::Itertools;
+use tokio::task::JoinHandle;
+
+use crate::{AnyKey, Registry, Span};
+
+/// A span that has been running for longer than the warn threshold.
+struct StuckSpan {
+    key: AnyKey,
+    path: Vec<Span>,
+    elapsed: Duration,
+}
+
+/// Detects the stuck spans in a registry, deduplicating the ones already reported.
+#[derive(Default)]
+struct Watchdog {
+    /// The stuck spans reported in the last check.
+    reported: HashSet<(AnyKey, NodeId)>,
+}
+
+impl Watchdog {
+    /// Returns the leaf spans that have newly crossed the warn threshold since the last check.
+    fn check(&mut self, registry: &Registry) -> Vec<StuckSpan> {
+        let mut stuck = Vec::new();
+        let mut reported = HashSet::new();
+
+        registry.for_each_tree(|key, tree| {
+            for (node, elapsed) in tree.leaves() {
+                if elapsed < tree.warn_threshold(node) {
+                    continue;
+                }
+                let id = (key.clone(), node);
+                if !self.reported.contains(&id) {
+                    stuck.push(StuckSpan {
+                        key: key.clone(),
+                        path: tree.path_to(node),
+                        elapsed,
+                    });
+                }
+                reported.insert(id);
+            }
+        });
+
+        // Forget the spans that are no longer stuck, so that they'll be reported again if they
+        // get stuck later with the same node id.
+        self.reported = reported;
+        stuck
+    }
+}
+
+impl Registry {
+    /// Spawns a background task that scans the await-trees in this registry every `interval`,
+    /// and logs a warning for each leaf span that has been running for longer than
+    /// [`ConfigBuilder::warn_threshold`](crate::ConfigBuilder::warn_threshold), or the threshold
+    /// of the span set with [`SpanExt::warn_after`](crate::SpanExt::warn_after).
+    ///
+    /// Each stuck span is only logged once when it first crosses the threshold, instead of on
+    /// every tick. The task exits once the registry is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, as the task is spawned with
+    /// [`tokio::spawn`], or if `interval` is zero.
+    pub fn spawn_watchdog(&self, interval: Duration) -> JoinHandle<()> {
+        let registry = self.downgrade();
+
+        tokio::spawn(async move {
+            let mut watchdog = Watchdog::default();
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+                let Some(registry) = registry.upgrade() else {
+                    break;
+                };
+                for StuckSpan { key, path, elapsed } in watchdog.check(&registry) {
+                    tracing::warn!(
+                        %key,
+                        path = %path.iter().join(" > "),
+                        ?elapsed,
+                        "await-tree span has been running for too long"
+                    );
+                }
+            }
+        })
+    }
+}
+
+#[cfg(all(test, not(feature = "disabled")))]
+mod tests {
+    use futures::future::pending;
+
+    use super::*;
+    use crate::{ConfigBuilder, InstrumentAwait};
+
+    #[tokio::test]
+    async fn test_watchdog() {
+        let config = ConfigBuilder::default()
+            .warn_threshold(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let registry = Registry::new(config);
+        let mut watchdog = Watchdog::default();
+
+        let handle = tokio::spawn(registry.register("task", "root").instrument(async {
+            pending::<()>().instrument_await("stuck").await;
+        }));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(watchdog.check(&registry).is_empty());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let stuck = watchdog.check(&registry);
+        assert_eq!(stuck.len(), 1);
+        assert_eq!(stuck[0].key.to_string(), "task");
+        assert_eq!(stuck[0].path, ["root".into(), "stuck".into()]);
+        assert!(stuck[0].elapsed >= Duration::from_millis(100));
+
+        // Not reported again.
+        assert!(watchdog.check(&registry).is_empty());
+
+        handle.abort();
+        let _ = handle.await;
+        assert!(watchdog.check(&registry).is_empty());
+
+        // The watchdog exits once the registry is dropped.
+        let watchdog = registry.spawn_watchdog(Duration::from_millis(10));
+        drop(registry);
+        watchdog.await.unwrap();
+    }
+}